@@ -0,0 +1,48 @@
+#![feature(portable_simd)]
+#![feature(once_cell)]
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use crate::adaa::{Adaa, Antialiasing, SaturationCurve};
+
+#[allow(dead_code)]
+#[path = "../src/adaa.rs"]
+mod adaa;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/lut.rs"]
+mod lut;
+#[allow(dead_code)]
+#[path = "../src/nonlinearity.rs"]
+mod nonlinearity;
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let xs = (0..360)
+        .step_by(4)
+        .map(|d| 4. * (d as f32).to_radians().sin())
+        .collect::<Vec<_>>();
+
+    for (curve, name) in [
+        (SaturationCurve::SoftClip, "Soft clip saturation"),
+        (SaturationCurve::Tanh, "Tanh saturation"),
+    ] {
+        let mut group = c.benchmark_group(name);
+        for (mode, name) in [
+            (Antialiasing::Off, "naive"),
+            (Antialiasing::FirstOrder, "adaa1"),
+            (Antialiasing::SecondOrder, "adaa2"),
+        ] {
+            group.bench_function(name, |b| {
+                let mut adaa = Adaa::new(curve, mode);
+                b.iter(|| {
+                    for x in xs.iter().copied() {
+                        black_box(adaa.process(black_box(x)));
+                    }
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
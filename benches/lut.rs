@@ -0,0 +1,84 @@
+#![feature(portable_simd)]
+#![feature(once_cell)]
+
+use std::simd::f32x8;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use crate::lut::{Interpolation, Lut};
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/lut.rs"]
+mod lut;
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let xs = (0..360)
+        .step_by(4)
+        .map(|d| 4. * (d as f32).to_radians().sin())
+        .collect::<Vec<_>>();
+    let xs_simd = xs
+        .chunks_exact(8)
+        .map(f32x8::from_slice)
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("Tanh function");
+    group.bench_function("trig", |b| {
+        b.iter(|| {
+            for x in xs.iter().copied() {
+                black_box(black_box(x).tanh());
+            }
+        })
+    });
+    group.finish();
+
+    for (interpolation, name) in [
+        (Interpolation::Nearest, "Tanh LUT (nearest)"),
+        (Interpolation::Linear, "Tanh LUT (linear)"),
+        (Interpolation::Cubic, "Tanh LUT (cubic)"),
+    ] {
+        let mut group = c.benchmark_group(name);
+        for (size, odd) in [(64, false), (1024, false), (1024, true)] {
+            let lut = if odd {
+                Lut::odd(f32::tanh, 4., size, interpolation)
+            } else {
+                Lut::new(f32::tanh, -4.0..=4., size, interpolation)
+            };
+            let suffix = if odd { " odd" } else { "" };
+            group.bench_function(format!("{size}{suffix}"), |b| {
+                b.iter(|| {
+                    for x in xs.iter().copied() {
+                        black_box(lut.get(black_box(x)));
+                    }
+                })
+            });
+            group.bench_function(format!("{size}{suffix} simd"), |b| {
+                b.iter(|| {
+                    for x in xs_simd.iter().copied() {
+                        black_box(lut.get_simd(black_box(x)));
+                    }
+                })
+            });
+        }
+        group.finish();
+    }
+
+    let mut group = c.benchmark_group("dB to gain");
+    group.bench_function("powf", |b| {
+        b.iter(|| {
+            for x in xs.iter().copied() {
+                black_box(10f32.powf(black_box(12. * x) / 20.));
+            }
+        })
+    });
+    group.bench_function("lut", |b| {
+        b.iter(|| {
+            for x in xs.iter().copied() {
+                black_box(lut::db_to_gain(black_box(12. * x)));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,245 @@
+use nih_plug::prelude::Enum;
+
+use crate::nonlinearity::DIODE_PARAM;
+
+/// Input differences under which the ADAA quotients are replaced by their limit, to avoid dividing
+/// by values close to zero.
+const EPSILON: f64 = 1e-5;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaturationCurve {
+    /// `x / (D + |x|)`, saturating at ±1. This is [`crate::nonlinearity::Nonlinearity::Diode`]
+    /// scaled up by `1 / D`, so unlike the filters' curve it isn't unity gain around zero.
+    #[name = "Soft Clip"]
+    SoftClip,
+    Tanh,
+}
+
+impl SaturationCurve {
+    #[inline]
+    pub fn f(self, x: f64) -> f64 {
+        match self {
+            Self::SoftClip => x / (D + x.abs()),
+            Self::Tanh => x.tanh(),
+        }
+    }
+
+    /// First antiderivative of [`Self::f`], zero at the origin.
+    #[inline]
+    pub fn f1(self, x: f64) -> f64 {
+        let a = x.abs();
+        match self {
+            Self::SoftClip => a - D * (a / D).ln_1p(),
+            // ln(cosh(x)), written so that it doesn't overflow
+            Self::Tanh => a + (-2. * a).exp().ln_1p() - std::f64::consts::LN_2,
+        }
+    }
+
+    /// Second antiderivative of [`Self::f`], zero at the origin.
+    #[inline]
+    pub fn f2(self, x: f64) -> f64 {
+        let a = x.abs();
+        let y = match self {
+            Self::SoftClip => a * a / 2. - D * ((D + a) * (a / D).ln_1p() - a),
+            Self::Tanh => {
+                let pi2 = std::f64::consts::PI * std::f64::consts::PI;
+                a * a / 2. - a * std::f64::consts::LN_2 + li2_neg((-2. * a).exp()) / 2. + pi2 / 24.
+            }
+        };
+        // The first antiderivative is even, so the second one is odd
+        y.copysign(x)
+    }
+}
+
+const D: f64 = DIODE_PARAM as f64;
+
+/// Dilogarithm `Li2(-y)` for `y` in `[0, 1]`. The series of `Li2` converges too slowly near -1, so
+/// Landen's identity maps the argument onto `[0, 1/2]` first.
+fn li2_neg(y: f64) -> f64 {
+    let w = y / (1. + y);
+    let mut term = 1.;
+    let mut sum = 0.;
+    for k in 1..=40 {
+        term *= w;
+        sum += term / (k * k) as f64;
+    }
+    -0.5 * (1. + y).ln().powi(2) - sum
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Antialiasing {
+    Off,
+    #[name = "ADAA (1st order)"]
+    FirstOrder,
+    #[name = "ADAA (2nd order)"]
+    SecondOrder,
+}
+
+impl Antialiasing {
+    /// Delay added by the anti-aliasing, in samples.
+    pub fn latency(self) -> f32 {
+        match self {
+            Self::Off => 0.,
+            Self::FirstOrder => 0.5,
+            Self::SecondOrder => 1.,
+        }
+    }
+}
+
+/// A saturator with optional antiderivative anti-aliasing (ADAA).
+///
+/// Instead of sampling `f(x)` directly, ADAA outputs the average of `f` over the line segment
+/// joining consecutive input samples, computed from its antiderivatives. This acts as a lowpass on
+/// the aliased components, at the cost of a small delay.
+#[derive(Debug, Clone)]
+pub struct Adaa {
+    curve: SaturationCurve,
+    mode: Antialiasing,
+    /// Previous two inputs, most recent first.
+    x: [f64; 2],
+    /// First-order divided difference of the second antiderivative over the previous two inputs,
+    /// kept from the previous sample for the second-order mode.
+    d1: f64,
+}
+
+impl Adaa {
+    pub fn new(curve: SaturationCurve, mode: Antialiasing) -> Self {
+        Self {
+            curve,
+            mode,
+            x: [0.; 2],
+            d1: 0.,
+        }
+    }
+
+    /// Change the curve and anti-aliasing mode, resetting the state if either changed.
+    pub fn set(&mut self, curve: SaturationCurve, mode: Antialiasing) {
+        if curve != self.curve || mode != self.mode {
+            *self = Self::new(curve, mode);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.x = [0.; 2];
+        self.d1 = 0.;
+    }
+
+    #[inline]
+    pub fn process(&mut self, x: f32) -> f32 {
+        let x = x as f64;
+        let [x1, x2] = self.x;
+        let curve = self.curve;
+        let y = match self.mode {
+            Antialiasing::Off => curve.f(x),
+            Antialiasing::FirstOrder => {
+                let dx = x - x1;
+                if dx.abs() < EPSILON {
+                    curve.f((x + x1) / 2.)
+                } else {
+                    (curve.f1(x) - curve.f1(x1)) / dx
+                }
+            }
+            Antialiasing::SecondOrder => {
+                let dx = x - x1;
+                let d1 = if dx.abs() < EPSILON {
+                    curve.f1((x + x1) / 2.)
+                } else {
+                    (curve.f2(x) - curve.f2(x1)) / dx
+                };
+                let dx2 = x - x2;
+                let y = if dx2.abs() < EPSILON {
+                    // Limit as x tends towards x2, around which the quotient is ill-conditioned
+                    let mid = (x + x2) / 2.;
+                    let delta = mid - x1;
+                    if delta.abs() < EPSILON {
+                        curve.f((mid + x1) / 2.)
+                    } else {
+                        2. / delta * (curve.f1(mid) + (curve.f2(x1) - curve.f2(mid)) / delta)
+                    }
+                } else {
+                    2. * (d1 - self.d1) / dx2
+                };
+                self.d1 = d1;
+                y
+            }
+        };
+        self.x = [x, x1];
+        y as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use super::{Adaa, Antialiasing, SaturationCurve};
+
+    const CURVES: [SaturationCurve; 2] = [SaturationCurve::SoftClip, SaturationCurve::Tanh];
+
+    #[test]
+    fn antiderivatives() {
+        const H: f64 = 1e-4;
+        for curve in CURVES {
+            for i in -40..=40 {
+                let x = i as f64 / 4. + 0.01;
+                let df1 = (curve.f1(x + H) - curve.f1(x - H)) / (2. * H);
+                let df2 = (curve.f2(x + H) - curve.f2(x - H)) / (2. * H);
+                approx::assert_abs_diff_eq!(curve.f(x), df1, epsilon = 1e-6);
+                approx::assert_abs_diff_eq!(curve.f1(x), df2, epsilon = 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_input_is_saturated() {
+        for curve in CURVES {
+            for mode in [Antialiasing::FirstOrder, Antialiasing::SecondOrder] {
+                let mut adaa = Adaa::new(curve, mode);
+                let mut y = 0.;
+                for _ in 0..4 {
+                    y = adaa.process(3.);
+                }
+                approx::assert_abs_diff_eq!(curve.f(3.) as f32, y, epsilon = 1e-5);
+            }
+        }
+    }
+
+    const N: usize = 2048;
+
+    /// Ratio of the power outside of the harmonics of `bin` to the total power, in dB, of a loud
+    /// sine going through the saturator. Only the bins below 80% of Nyquist are considered, as ADAA
+    /// also attenuates the top of the spectrum.
+    fn aliasing_db(curve: SaturationCurve, mode: Antialiasing, bin: usize) -> f64 {
+        let mut adaa = Adaa::new(curve, mode);
+        let w = TAU * bin as f64 / N as f64;
+        let signal: Vec<f64> = (0..2 * N)
+            .map(|n| adaa.process((8. * (w * n as f64).sin()) as f32) as f64)
+            .skip(N)
+            .collect();
+        let mut total = 0.;
+        let mut aliased = 0.;
+        for k in 1..N * 2 / 5 {
+            let (re, im) = signal.iter().enumerate().fold((0., 0.), |(re, im), (n, x)| {
+                let phase = TAU * (k * n % N) as f64 / N as f64;
+                (re + x * phase.cos(), im - x * phase.sin())
+            });
+            let power = re * re + im * im;
+            total += power;
+            if k % bin != 0 {
+                aliased += power;
+            }
+        }
+        10. * (aliased / total).log10()
+    }
+
+    #[test]
+    fn reduces_aliasing() {
+        for curve in CURVES {
+            let off = aliasing_db(curve, Antialiasing::Off, 97);
+            let first = aliasing_db(curve, Antialiasing::FirstOrder, 97);
+            let second = aliasing_db(curve, Antialiasing::SecondOrder, 97);
+            assert!(first < off - 6., "{curve:?}: {off} dB off, {first} dB first order");
+            assert!(second < first, "{curve:?}: {first} dB first order, {second} dB second order");
+        }
+    }
+}
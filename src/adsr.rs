@@ -4,6 +4,12 @@ use std::fmt::Formatter;
 use nih_plug::prelude::*;
 use std::sync::Arc;
 
+use crate::math::key_track;
+
+/// Level under which the envelope is considered silent (-80 dB), matching the point at which the
+/// exponential smoothers consider themselves settled.
+const SILENCE: f32 = 1e-4;
+
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 enum AdsrState {
     A,
@@ -19,11 +25,19 @@ pub struct Adsr {
     smoother: Smoother<f32>,
     state: AdsrState,
     samplerate: f32,
+    /// Multiplier applied to all segment times, set from the key tracking amount at note-on.
+    time_scale: f32,
+    /// Multiplier applied to all segment times from the modulation matrix.
+    time_mod: f32,
+    /// Sustain level the decay and sustain segments are currently heading towards.
+    sustain: f32,
 }
 
 impl Adsr {
-    pub fn new(samplerate: f32, params: Arc<AdsrParams>) -> Self {
-        let smoother = Smoother::new(SmoothingStyle::Exponential(params.a.value()));
+    pub fn new(samplerate: f32, params: Arc<AdsrParams>, note: u8) -> Self {
+        // Higher notes get shorter segments, hence the negative tracking amount
+        let time_scale = key_track(note, -params.kt.value());
+        let smoother = Smoother::new(SmoothingStyle::Exponential(params.a.value() * time_scale));
         smoother.reset(0.);
         smoother.set_target(samplerate, 1.);
         Self {
@@ -31,53 +45,87 @@ impl Adsr {
             smoother,
             samplerate,
             state: AdsrState::A,
+            time_scale,
+            time_mod: 1.,
+            sustain: 0.,
         }
     }
 
+    #[inline]
+    fn style(&self, time: f32) -> SmoothingStyle {
+        SmoothingStyle::Exponential(time * self.time_scale * self.time_mod)
+    }
+
+    /// Scale the segment times by `octaves` octaves. This takes effect from the next segment on.
+    pub fn set_time_mod(&mut self, octaves: f32) {
+        self.time_mod = octaves.exp2();
+    }
+
     pub fn value(&self) -> f32 {
         self.smoother.previous_value()
     }
 
     pub fn next(&mut self) -> f32 {
-        if self.smoother.steps_left() == 0 {
-            match self.state {
-                AdsrState::A => {
-                    self.smoother = Smoother::new(SmoothingStyle::Exponential(self.params.d.value()));
-                    self.smoother.reset(1.0);
-                    self.smoother.set_target(self.samplerate, self.params.s.value());
-                    self.state = AdsrState::D;
-                }
-                AdsrState::D => {
-                    self.state = AdsrState::S;
+        match self.state {
+            AdsrState::A if self.smoother.steps_left() == 0 => {
+                self.sustain = self.params.s.value();
+                self.smoother = Smoother::new(self.style(self.params.d.value()));
+                self.smoother.reset(1.0);
+                self.smoother.set_target(self.samplerate, self.sustain);
+                self.state = AdsrState::D;
+            }
+            AdsrState::A => {
+                self.smoother.style = self.style(self.params.a.value());
+            }
+            // A decay that lands on silence has nothing left to sustain, so the voice can be freed
+            AdsrState::D if self.smoother.steps_left() == 0 => {
+                self.state = if self.sustain <= SILENCE {
+                    AdsrState::Released
+                } else {
+                    AdsrState::S
+                };
+            }
+            AdsrState::D | AdsrState::S => {
+                // Follow changes to the sustain level while the note is held, gliding to the new
+                // level at the decay rate
+                let sustain = self.params.s.value();
+                if sustain != self.sustain {
+                    self.sustain = sustain;
+                    self.smoother.style = self.style(self.params.d.value());
+                    self.smoother.set_target(self.samplerate, sustain);
                 }
-                AdsrState::R => {
+                if self.state == AdsrState::S
+                    && self.sustain <= SILENCE
+                    && self.smoother.previous_value() <= SILENCE
+                {
                     self.state = AdsrState::Released;
                 }
-                _ => {}
             }
-        } else {
-            match self.state {
-                AdsrState::A => {
-                    self.smoother.style = SmoothingStyle::Exponential(self.params.a.value());
-                }
-                AdsrState::D => {
-                    self.smoother.style = SmoothingStyle::Exponential(self.params.d.value());
-                    self.smoother.set_target(self.samplerate, self.params.s.value());
-                }
-                AdsrState::R => {
-                    self.smoother.style = SmoothingStyle::Exponential(self.params.r.value());
-                }
-                _ => {}
+            AdsrState::R
+                if self.smoother.steps_left() == 0 || self.smoother.previous_value() <= SILENCE =>
+            {
+                self.state = AdsrState::Released;
+            }
+            AdsrState::R => {
+                self.smoother.style = self.style(self.params.r.value());
             }
+            AdsrState::Released => {}
         }
 
+        if self.state == AdsrState::Released {
+            self.smoother.reset(0.);
+            return 0.;
+        }
         self.smoother.next()
     }
 
     pub fn release(&mut self) {
+        if self.state == AdsrState::Released {
+            return;
+        }
         let val = self.smoother.previous_value();
         self.state = AdsrState::R;
-        self.smoother = Smoother::new(SmoothingStyle::Exponential(self.params.r.value()));
+        self.smoother = Smoother::new(self.style(self.params.r.value()));
         self.smoother.reset(val);
         self.smoother.set_target(self.samplerate, 0.);
     }
@@ -86,13 +134,11 @@ impl Adsr {
         matches!(self.state, AdsrState::R)
     }
 
+    /// Whether the envelope can still produce sound. This turns false once the release segment
+    /// has faded out, or once the decay has settled on a silent sustain level.
     #[inline(always)]
     pub fn active(&self) -> bool {
-        match self.state {
-            AdsrState::Released => false,
-            AdsrState::S if self.params.s.value() == 0. => false,
-            _ => true,
-        }
+        self.state != AdsrState::Released
     }
 }
 
@@ -106,6 +152,8 @@ pub struct AdsrParams {
     s: FloatParam,
     #[id="r"]
     r: FloatParam,
+    #[id="kt"]
+    kt: FloatParam,
 }
 
 impl fmt::Debug for AdsrParams {
@@ -117,10 +165,11 @@ impl fmt::Debug for AdsrParams {
 impl Default for AdsrParams {
     fn default() -> Self {
         Self {
-            a: adr_param(format!("Attack"), 10.),
-            d: adr_param(format!("Decay"), 300.),
-            s: s_param(format!("Sustain"), 0.5),
-            r: adr_param(format!("Release"), 300.),
+            a: adr_param("Attack", 10.),
+            d: adr_param("Decay", 300.),
+            s: s_param("Sustain", 0.5),
+            r: adr_param("Release", 300.),
+            kt: s_param("Key Tracking", 0.),
         }
     }
 }
@@ -135,3 +184,112 @@ fn adr_param(name: impl ToString, default: f32) -> FloatParam {
     FloatParam::new(name.to_string(), default, FloatRange::Linear { min: 0., max: 5e3 })
         .with_unit("ms")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{adr_param, s_param, Adsr, AdsrParams, AdsrState, SILENCE};
+
+    const FS: f32 = 48e3;
+
+    fn adsr(a: f32, d: f32, s: f32, r: f32) -> Adsr {
+        let params = AdsrParams {
+            a: adr_param("Attack", a),
+            d: adr_param("Decay", d),
+            s: s_param("Sustain", s),
+            r: adr_param("Release", r),
+            kt: s_param("Key Tracking", 0.),
+        };
+        Adsr::new(FS, Arc::new(params), 60)
+    }
+
+    fn samples(ms: f32) -> usize {
+        (ms * FS / 1e3).ceil() as usize
+    }
+
+    #[test]
+    fn decay_to_silence_frees_voice() {
+        let mut env = adsr(5., 20., 0., 100.);
+        let mut peak = 0f32;
+        for _ in 0..samples(5.) {
+            peak = peak.max(env.next());
+            assert!(env.active());
+        }
+        assert!(peak > 0.99);
+
+        let mut n = 0;
+        while env.active() {
+            assert!(n < samples(20.) + 2, "decay did not free the voice");
+            env.next();
+            n += 1;
+        }
+        assert_eq!(AdsrState::Released, env.state);
+        assert_eq!(0., env.next());
+    }
+
+    #[test]
+    fn nonzero_sustain_holds() {
+        let mut env = adsr(5., 20., 0.5, 100.);
+        for _ in 0..samples(25.) + 2 {
+            env.next();
+        }
+        assert_eq!(AdsrState::S, env.state);
+        for _ in 0..samples(1000.) {
+            let y = env.next();
+            assert!(env.active());
+            approx::assert_abs_diff_eq!(0.5, y, epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn release_terminates() {
+        let mut env = adsr(5., 20., 0.5, 100.);
+        for _ in 0..samples(50.) {
+            env.next();
+        }
+        env.release();
+        assert!(env.releasing());
+
+        let mut prev = env.value();
+        let mut n = 0;
+        while env.active() {
+            assert!(n < samples(100.) + 2, "release did not terminate");
+            let y = env.next();
+            assert!(y <= prev);
+            prev = y;
+            n += 1;
+        }
+        assert!(prev <= SILENCE);
+        assert_eq!(AdsrState::Released, env.state);
+    }
+
+    #[test]
+    fn release_during_attack_terminates() {
+        let mut env = adsr(50., 20., 0.5, 10.);
+        for _ in 0..samples(10.) {
+            env.next();
+        }
+        assert_eq!(AdsrState::A, env.state);
+        env.release();
+
+        let mut n = 0;
+        while env.active() {
+            assert!(n < samples(10.) + 2, "release did not terminate");
+            env.next();
+            n += 1;
+        }
+        assert_eq!(0., env.next());
+    }
+
+    #[test]
+    fn release_after_release_is_noop() {
+        let mut env = adsr(1., 1., 0., 1.);
+        while env.active() {
+            env.next();
+        }
+        env.release();
+        assert!(!env.active());
+        assert_eq!(0., env.next());
+    }
+}
@@ -0,0 +1,193 @@
+use std::f32::consts::TAU;
+
+use nalgebra::{SMatrix, SVector};
+
+use crate::lpf::{NR_MAX_ITER, NR_TOL};
+use crate::math::{nr_solve, ScalarField};
+use crate::nonlinearity::Nonlinearity;
+
+type Y = SVector<f32, 4>;
+
+/// Four-pole diode ladder in the style of the Roland TB-303.
+///
+/// Unlike the transistor ladder, each capacitor in the diode ladder is loaded by the next stage,
+/// so the current through every stage depends on its neighbours:
+/// `yi' = wc (f(y(i-1) - yi) - f(yi - y(i+1)))`, with `y0 = x - k y4`, and the last capacitor being
+/// twice as large, `y4' = wc / 2 f(y3 - y4)`. The coupling spreads the poles apart, which gives the
+/// softer, squelchier resonance of the original: without resonance the response starts rolling off
+/// well below the cutoff, which is instead where the resonant peak sits. Being lowpass only, the
+/// filter ignores the mode.
+///
+/// The coupled stages lose a lot more of the signal around the loop, so it takes a feedback of about
+/// 24 for the filter to self-oscillate. The resonance is scaled by 6 so that it starts oscillating
+/// around `q = 4`, like the [`Ladder`](crate::lpf::Ladder).
+#[derive(Debug, Copy, Clone)]
+pub struct DiodeLadder {
+    samplerate: f32,
+    g: f32,
+    k: f32,
+    y: Y,
+    compensation: f32,
+    nonlinearity: Nonlinearity,
+}
+
+impl DiodeLadder {
+    pub fn new(samplerate: f32, fc: f32, q: f32) -> Self {
+        let mut this = Self {
+            samplerate,
+            g: 0.,
+            k: 0.,
+            y: Y::zeros(),
+            compensation: 0.,
+            nonlinearity: Nonlinearity::Tanh,
+        };
+        this.set_fc(fc);
+        this.set_resonance(q);
+        this
+    }
+
+    /// Change the sample rate, keeping the state of the filter.
+    pub fn set_samplerate(&mut self, samplerate: f32, fc: f32) {
+        self.samplerate = samplerate;
+        self.set_fc(fc);
+    }
+
+    pub fn set_fc(&mut self, fc: f32) {
+        self.g = TAU * fc.min(self.samplerate) / self.samplerate;
+    }
+
+    pub fn set_resonance(&mut self, q: f32) {
+        self.k = 6. * q;
+    }
+
+    pub fn set_nonlinearity(&mut self, nonlinearity: Nonlinearity) {
+        self.nonlinearity = nonlinearity;
+    }
+
+    /// Amount of passband gain compensation, between 0 and 1. As with the transistor ladder, the DC
+    /// gain is `1 / (1 + k)`.
+    pub fn set_compensation(&mut self, amount: f32) {
+        self.compensation = amount;
+    }
+
+    /// Solve for the capacitor voltages at the next sample, returning whether the solver converged.
+    #[inline(always)]
+    fn solve(&mut self, x: f32) -> bool {
+        let phi = Phi {
+            x,
+            g: self.g,
+            k: self.k,
+            s: self.y,
+            nl: self.nonlinearity,
+        };
+        nr_solve(&phi, &mut self.y, NR_MAX_ITER, NR_TOL)
+    }
+
+    #[inline(always)]
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        self.solve(x);
+        self.y[3] * (1. + self.compensation * self.k)
+    }
+}
+
+struct Phi {
+    x: f32,
+    g: f32,
+    k: f32,
+    s: Y,
+    nl: Nonlinearity,
+}
+
+impl Phi {
+    /// Voltages across the diode pairs, from the input side to the last capacitor.
+    #[inline(always)]
+    fn v(&self, y: &Y) -> SVector<f32, 4> {
+        SVector::<_, 4>::new(
+            self.x - self.k * y[3] - y[0],
+            y[0] - y[1],
+            y[1] - y[2],
+            y[2] - y[3],
+        )
+    }
+}
+
+impl ScalarField<f32, 4> for Phi {
+    #[inline(always)]
+    fn eval(&self, y: &Y) -> Y {
+        let i = self.v(y).map(|v| self.nl.f(v));
+        let f = Y::new(i[0] - i[1], i[1] - i[2], i[2] - i[3], i[3] / 2.);
+        self.s + f * self.g - y
+    }
+
+    #[inline(always)]
+    #[rustfmt::skip]
+    fn jacobian(&self, y: &Y) -> SMatrix<f32, 4, 4> {
+        let d = self.v(y).map(|v| self.nl.df(v));
+        SMatrix::<_, 4, 4>::new(
+            // Row 1
+            -d[0] - d[1], d[1], 0., -self.k * d[0],
+            // Row 2
+            d[1], -d[1] - d[2], d[2], 0.,
+            // Row 3
+            0., d[2], -d[2] - d[3], d[3],
+            // Row 4
+            0., 0., d[3] / 2., -d[3] / 2.,
+        ) * self.g - SMatrix::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiodeLadder;
+    use crate::filter::{ring_peak, sine_gain_db};
+    use crate::nonlinearity::Nonlinearity;
+
+    const FS: f32 = 48e3;
+    const FC: f32 = 1e3;
+
+    fn gain_db(mut filter: DiodeLadder, freq: f32) -> f32 {
+        sine_gain_db(FS, freq, |x| filter.process_sample(x))
+    }
+
+    #[test]
+    fn lowpass_response() {
+        let filter = DiodeLadder::new(FS, FC, 0.);
+        assert!(gain_db(filter, 15.).abs() < 0.5);
+        assert!(gain_db(filter, 8e3) < -40.);
+
+        let mut compensated = DiodeLadder::new(FS, FC, 2.);
+        approx::assert_abs_diff_eq!(-20. * 13f32.log10(), gain_db(compensated, 15.), epsilon = 0.5);
+        compensated.set_compensation(1.);
+        approx::assert_abs_diff_eq!(0., gain_db(compensated, 15.), epsilon = 0.5);
+    }
+
+    #[test]
+    fn self_oscillates_past_threshold() {
+        let ring = |q| {
+            let mut filter = DiodeLadder::new(FS, FC, q);
+            ring_peak(FS, |x| filter.process_sample(x))
+        };
+        assert!(ring(3.) < 1e-4);
+        assert!(ring(8.) > 1e-2);
+    }
+
+    #[test]
+    fn newton_converges_over_drive_and_resonance() {
+        for nl in [Nonlinearity::Tanh, Nonlinearity::Diode, Nonlinearity::Transistor] {
+            for drive_db in [0., 12., 24., 36.] {
+                for q in [0., 2., 4., 8., 16.] {
+                    let drive = 10f32.powf(drive_db / 20.);
+                    let mut filter = DiodeLadder::new(FS, FC, q);
+                    filter.set_nonlinearity(nl);
+                    for i in 0..4800 {
+                        let x = drive * (2. * (i as f32 * 110. / FS).fract() - 1.);
+                        assert!(
+                            filter.solve(x),
+                            "{nl:?} at {drive_db} dB, q = {q}: no convergence at sample {i}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
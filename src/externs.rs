@@ -1,5 +1,6 @@
 use std::simd::f32x8;
 
+#[allow(improper_ctypes)]
 extern "C" {
     #[link_name = "llvm.cos.v8f32"]
     fn cos_v8f32(x: f32x8) -> f32x8;
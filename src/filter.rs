@@ -0,0 +1,383 @@
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use nih_plug::prelude::*;
+
+use crate::diode_ladder::DiodeLadder;
+use crate::lpf::{FilterMode, Ladder};
+use crate::nonlinearity::Nonlinearity;
+use crate::sallen_key::SallenKey;
+use crate::svf::Svf;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterType {
+    /// Moog-style four-pole transistor ladder.
+    Ladder,
+    #[name = "SVF"]
+    Svf,
+    #[name = "Sallen-Key"]
+    SallenKey,
+    #[name = "Diode Ladder"]
+    DiodeLadder,
+}
+
+/// Lowest cutoff the modulation can bring the filter down to, in Hz.
+pub const MIN_CUTOFF: f32 = 10.;
+/// Highest cutoff the modulation can bring the filter up to, as a fraction of the sample rate.
+pub const MAX_CUTOFF_RATIO: f32 = 0.45;
+/// Filter envelope amount at the ends of its range in [`FilterEnvMode::Exponential`], in octaves.
+pub const MAX_ENV_OCTAVES: f32 = 8.;
+
+/// How the filter envelope amount is applied to the cutoff.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterEnvMode {
+    /// The envelope adds up to the amount, in Hz, to the cutoff. The same amount is a much deeper
+    /// sweep at low cutoffs than at high ones.
+    #[name = "Hz"]
+    Linear,
+    /// The envelope moves the cutoff by up to the amount in octaves, up or down, so that sweeps
+    /// sound the same whatever the cutoff.
+    #[name = "Octaves"]
+    Exponential,
+}
+
+impl FilterEnvMode {
+    /// Cutoff for an envelope value `env`, given the `amount` in Hz or octaves depending on the
+    /// mode, and an `offset` in octaves applied on top. The result is clamped between
+    /// [`MIN_CUTOFF`] and [`MAX_CUTOFF_RATIO`] of the sample rate.
+    #[inline]
+    pub fn cutoff(self, fc: f32, env: f32, amount: f32, offset: f32, samplerate: f32) -> f32 {
+        let fc = match self {
+            Self::Linear => (fc + env * amount).max(0.) * offset.exp2(),
+            Self::Exponential => fc * (env * amount + offset).exp2(),
+        };
+        fc.clamp(MIN_CUTOFF, MAX_CUTOFF_RATIO * samplerate)
+    }
+}
+
+/// How the two filters of a voice are connected.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterRouting {
+    /// Only filter A is used.
+    #[name = "A Only"]
+    Single,
+    /// Filter A feeds into filter B.
+    #[name = "A > B"]
+    Serial,
+    /// Both filters process the oscillator, and their outputs are summed.
+    #[name = "A + B"]
+    Parallel,
+    /// Partials up to the split harmonic go through filter A, and the ones above through filter B.
+    #[name = "Split Partials"]
+    Split,
+}
+
+/// Settings of one of the filter slots of a voice.
+#[derive(Params)]
+pub struct FilterParams {
+    #[id = "ftype"]
+    pub ty: EnumParam<FilterType>,
+    #[id = "fhz"]
+    pub cutoff: FloatParam,
+    #[id = "q"]
+    pub q: FloatParam,
+    #[id = "fcomp"]
+    pub compensation: FloatParam,
+    #[id = "fmode"]
+    pub mode: EnumParam<FilterMode>,
+    #[id = "fsat"]
+    pub saturation: EnumParam<Nonlinearity>,
+    #[id = "fmodmode"]
+    pub env_mode: EnumParam<FilterEnvMode>,
+    #[id = "fmod"]
+    pub env_amount: FloatParam,
+    #[id = "fmodoct"]
+    pub env_octaves: FloatParam,
+    #[id = "fkt"]
+    pub key_tracking: FloatParam,
+    #[id = "drive"]
+    pub drive: FloatParam,
+}
+
+impl fmt::Debug for FilterParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FilterParams").finish_non_exhaustive()
+    }
+}
+
+impl FilterParams {
+    /// Create the parameters of a filter slot, with `name` prefixed to every parameter name.
+    pub fn new(name: &str) -> Self {
+        Self {
+            ty: EnumParam::new(format!("{name} Type"), FilterType::Ladder),
+            cutoff: FloatParam::new(
+                format!("{name} Cutoff"),
+                300.,
+                FloatRange::Skewed {
+                    min: 20.,
+                    max: 20e3,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz())
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2))
+            .with_smoother(SmoothingStyle::Exponential(100.)),
+            q: FloatParam::new(
+                format!("{name} Q"),
+                0.,
+                FloatRange::Skewed {
+                    min: 0.,
+                    max: 16.,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_smoother(SmoothingStyle::Linear(30.)),
+            compensation: FloatParam::new(
+                format!("{name} Resonance Compensation"),
+                0.,
+                FloatRange::Linear { min: 0., max: 1. },
+            )
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_value_to_string(formatters::v2s_f32_percentage(2))
+            .with_smoother(SmoothingStyle::Linear(30.)),
+            mode: EnumParam::new(format!("{name} Mode"), FilterMode::Lp4),
+            saturation: EnumParam::new(format!("{name} Saturation"), Nonlinearity::Tanh),
+            env_mode: EnumParam::new(format!("{name} Env Mode"), FilterEnvMode::Linear),
+            env_amount: FloatParam::new(
+                format!("{name} Modulation"),
+                3000.,
+                FloatRange::Skewed {
+                    min: 0.,
+                    max: 20e3,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz())
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2))
+            .with_smoother(SmoothingStyle::Exponential(100.)),
+            env_octaves: FloatParam::new(
+                format!("{name} Env Octaves"),
+                4.,
+                FloatRange::Linear {
+                    min: -MAX_ENV_OCTAVES,
+                    max: MAX_ENV_OCTAVES,
+                },
+            )
+            .with_string_to_value(Arc::new(octaves_from_string))
+            .with_value_to_string(Arc::new(|oct| format!("{oct:+.2} oct")))
+            .with_smoother(SmoothingStyle::Linear(30.)),
+            key_tracking: FloatParam::new(
+                format!("{name} Key Tracking"),
+                0.,
+                FloatRange::Linear { min: 0., max: 1. },
+            )
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_value_to_string(formatters::v2s_f32_percentage(2))
+            .with_smoother(SmoothingStyle::Linear(30.)),
+            drive: FloatParam::new(
+                format!("{name} Drive"),
+                0.,
+                FloatRange::SymmetricalSkewed {
+                    min: -36.,
+                    max: 36.,
+                    center: 0.,
+                    factor: 2.,
+                },
+            )
+            .with_unit("dB")
+            .with_smoother(SmoothingStyle::Exponential(50.)),
+        }
+    }
+}
+
+/// Parse a filter envelope amount in octaves, or in semitones when followed by `st`.
+fn octaves_from_string(s: &str) -> Option<f32> {
+    let s = s.trim().trim_end_matches("oct").trim_end();
+    match s.strip_suffix("st") {
+        Some(st) => st.trim_end().parse::<f32>().ok().map(|st| st / 12.),
+        None => s.parse().ok(),
+    }
+}
+
+/// The per-voice filter, of any of the available topologies. Settings that a topology has no use
+/// for (the saturation of the linear SVF, the mode of the lowpass-only diode ladder, ...) are
+/// ignored.
+#[derive(Debug, Copy, Clone)]
+pub enum Filter {
+    Ladder(Ladder),
+    Svf(Svf),
+    SallenKey(SallenKey),
+    DiodeLadder(DiodeLadder),
+}
+
+impl Filter {
+    pub fn new(ty: FilterType, samplerate: f32, fc: f32, q: f32) -> Self {
+        match ty {
+            FilterType::Ladder => Self::Ladder(Ladder::new(samplerate, fc, q)),
+            FilterType::Svf => Self::Svf(Svf::new(samplerate, fc, q)),
+            FilterType::SallenKey => Self::SallenKey(SallenKey::new(samplerate, fc, q)),
+            FilterType::DiodeLadder => Self::DiodeLadder(DiodeLadder::new(samplerate, fc, q)),
+        }
+    }
+
+    pub fn filter_type(&self) -> FilterType {
+        match self {
+            Self::Ladder(_) => FilterType::Ladder,
+            Self::Svf(_) => FilterType::Svf,
+            Self::SallenKey(_) => FilterType::SallenKey,
+            Self::DiodeLadder(_) => FilterType::DiodeLadder,
+        }
+    }
+
+    /// Switch to another topology, starting from a silent state. Does nothing if the filter is
+    /// already of that type.
+    pub fn set_type(&mut self, ty: FilterType, samplerate: f32, fc: f32, q: f32) {
+        if self.filter_type() != ty {
+            *self = Self::new(ty, samplerate, fc, q);
+        }
+    }
+
+    /// Change the sample rate, keeping the state of the filter so that changing the oversampling
+    /// factor doesn't click.
+    pub fn set_samplerate(&mut self, samplerate: f32, fc: f32) {
+        match self {
+            Self::Ladder(f) => f.set_samplerate(samplerate, fc),
+            Self::Svf(f) => f.set_samplerate(samplerate, fc),
+            Self::SallenKey(f) => f.set_samplerate(samplerate, fc),
+            Self::DiodeLadder(f) => f.set_samplerate(samplerate, fc),
+        }
+    }
+
+    pub fn set_fc(&mut self, fc: f32) {
+        match self {
+            Self::Ladder(f) => f.set_fc(fc),
+            Self::Svf(f) => f.set_fc(fc),
+            Self::SallenKey(f) => f.set_fc(fc),
+            Self::DiodeLadder(f) => f.set_fc(fc),
+        }
+    }
+
+    pub fn set_resonance(&mut self, q: f32) {
+        match self {
+            Self::Ladder(f) => f.set_resonance(q),
+            Self::Svf(f) => f.set_resonance(q),
+            Self::SallenKey(f) => f.set_resonance(q),
+            Self::DiodeLadder(f) => f.set_resonance(q),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        match self {
+            Self::Ladder(f) => f.set_mode(mode),
+            Self::Svf(f) => f.set_mode(mode),
+            Self::SallenKey(f) => f.set_mode(mode),
+            Self::DiodeLadder(_) => {}
+        }
+    }
+
+    pub fn set_nonlinearity(&mut self, nonlinearity: Nonlinearity) {
+        match self {
+            Self::Ladder(f) => f.set_nonlinearity(nonlinearity),
+            Self::SallenKey(f) => f.set_nonlinearity(nonlinearity),
+            Self::DiodeLadder(f) => f.set_nonlinearity(nonlinearity),
+            Self::Svf(_) => {}
+        }
+    }
+
+    pub fn set_compensation(&mut self, amount: f32) {
+        match self {
+            Self::Ladder(f) => f.set_compensation(amount),
+            Self::DiodeLadder(f) => f.set_compensation(amount),
+            Self::Svf(_) | Self::SallenKey(_) => {}
+        }
+    }
+
+    #[inline(always)]
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        match self {
+            Self::Ladder(f) => f.process_sample(x),
+            Self::Svf(f) => f.process_sample(x),
+            Self::SallenKey(f) => f.process_sample(x),
+            Self::DiodeLadder(f) => f.process_sample(x),
+        }
+    }
+}
+
+/// Steady-state gain of a filter at `freq`, in dB, from the peak of its response to a sine once it
+/// has settled. The input is kept small so that the nonlinearities stay out of the way.
+#[cfg(test)]
+pub fn sine_gain_db(samplerate: f32, freq: f32, mut process_sample: impl FnMut(f32) -> f32) -> f32 {
+    const AMP: f32 = 0.01;
+    let len = (0.2 * samplerate) as usize;
+    let mut peak = 0f32;
+    for i in 0..len {
+        let x = AMP * (std::f32::consts::TAU * freq * i as f32 / samplerate).sin();
+        let y = process_sample(x);
+        if i >= len / 2 {
+            peak = peak.max(y.abs());
+        }
+    }
+    20. * (peak / AMP).log10()
+}
+
+/// Peak of a filter's response to an impulse over the last tenth of the second following it, which
+/// is only significant when the filter self-oscillates.
+#[cfg(test)]
+pub fn ring_peak(samplerate: f32, mut process_sample: impl FnMut(f32) -> f32) -> f32 {
+    let mut peak = 0f32;
+    for i in 0..samplerate as usize {
+        let y = process_sample(if i == 0 { 0.1 } else { 0. });
+        if i as f32 >= 0.9 * samplerate {
+            peak = peak.max(y.abs());
+        }
+    }
+    peak
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, FilterEnvMode, FilterType, MAX_CUTOFF_RATIO, MIN_CUTOFF};
+
+    const FS: f32 = 48e3;
+
+    #[test]
+    fn exponential_env_tracks_octaves() {
+        let mode = FilterEnvMode::Exponential;
+        for fc in [100., 1e3] {
+            approx::assert_relative_eq!(4. * fc, mode.cutoff(fc, 1., 2., 0., FS));
+            approx::assert_relative_eq!(fc / 2., mode.cutoff(fc, 0.5, -2., 0., FS));
+            approx::assert_relative_eq!(fc, mode.cutoff(fc, 0.5, 2., -1., FS));
+        }
+    }
+
+    #[test]
+    fn cutoff_stays_in_range() {
+        for mode in [FilterEnvMode::Linear, FilterEnvMode::Exponential] {
+            approx::assert_relative_eq!(MAX_CUTOFF_RATIO * FS, mode.cutoff(10e3, 1., 8., 8., FS));
+            approx::assert_relative_eq!(MIN_CUTOFF, mode.cutoff(20., 1., -8., -8., FS));
+        }
+        approx::assert_relative_eq!(
+            MIN_CUTOFF,
+            FilterEnvMode::Linear.cutoff(300., 1., -3000., 0., FS)
+        );
+    }
+
+    #[test]
+    fn samplerate_change_keeps_state() {
+        for ty in [
+            FilterType::Ladder,
+            FilterType::Svf,
+            FilterType::SallenKey,
+            FilterType::DiodeLadder,
+        ] {
+            let mut filter = Filter::new(ty, FS, 1e3, 0.);
+            let mut y = 0.;
+            for _ in 0..4800 {
+                y = filter.process_sample(0.5);
+            }
+            filter.set_samplerate(4. * FS, 1e3);
+            approx::assert_abs_diff_eq!(y, filter.process_sample(0.5), epsilon = 1e-3);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+
+/// Maximum modulation index, in radians of phase deviation.
+pub const MAX_FM_INDEX: f32 = 16.;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FmMode {
+    Off,
+    /// A sine modulator shared by the whole partial bank.
+    #[name = "Modulator"]
+    Global,
+    /// One of the partials modulates all the others.
+    #[name = "Partial"]
+    Partial,
+}
+
+/// What modulates the phase of the partials.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FmSource {
+    /// A sine at `ratio` times the frequency of the first partial.
+    Modulator { ratio: f32 },
+    /// A sine at this harmonic of the first partial, counting from 1. It follows the phase of the
+    /// partial at that harmonic if there is one, which is itself left unmodulated.
+    Partial(usize),
+}
+
+/// Frequency modulation of the partial bank, implemented as phase modulation like in most FM
+/// synths. The modulation index of each partial is the global index scaled by its harmonic number
+/// to the power of `scaling`, so that positive values make the upper partials brighter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fm {
+    pub source: FmSource,
+    pub scaling: f32,
+}
+
+impl Fm {
+    /// Index multiplier of a partial at `hz` for a note whose first partial is at `f0`.
+    #[inline]
+    pub fn index_scale(&self, hz: f32, f0: f32) -> f32 {
+        (hz / f0).powf(self.scaling)
+    }
+}
+
+#[derive(Params)]
+pub struct FmParams {
+    #[id = "fmmode"]
+    pub mode: EnumParam<FmMode>,
+    #[id = "fmindex"]
+    pub index: FloatParam,
+    #[id = "fmratio"]
+    pub ratio: FloatParam,
+    /// Harmonic number of the modulating partial, in [`FmMode::Partial`] mode. The modulator is a
+    /// sine at that harmonic even if the waveform has no partial there.
+    #[id = "fmsrc"]
+    pub source: IntParam,
+    #[id = "fmscale"]
+    pub scaling: FloatParam,
+}
+
+impl fmt::Debug for FmParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FmParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for FmParams {
+    fn default() -> Self {
+        Self {
+            mode: EnumParam::new("FM Mode", FmMode::Off),
+            index: FloatParam::new(
+                "FM Index",
+                1.,
+                FloatRange::Skewed {
+                    min: 0.,
+                    max: MAX_FM_INDEX,
+                    factor: FloatRange::skew_factor(-1.),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(2))
+            .with_smoother(SmoothingStyle::Linear(10.)),
+            ratio: FloatParam::new(
+                "FM Ratio",
+                1.,
+                FloatRange::Skewed {
+                    min: 0.25,
+                    max: 16.,
+                    factor: FloatRange::skew_factor(-1.5),
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(3)),
+            source: IntParam::new("FM Source Harmonic", 1, IntRange::Linear { min: 1, max: 64 }),
+            scaling: FloatParam::new(
+                "FM Index Scaling",
+                0.,
+                FloatRange::Linear { min: -1., max: 1. },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+        }
+    }
+}
+
+impl FmParams {
+    /// Current FM configuration, or `None` when FM is off. The index is read separately, as it is
+    /// smoothed and modulated per sample.
+    pub fn fm(&self) -> Option<Fm> {
+        let source = match self.mode.value() {
+            FmMode::Off => return None,
+            FmMode::Global => FmSource::Modulator {
+                ratio: self.ratio.value(),
+            },
+            FmMode::Partial => FmSource::Partial(self.source.value() as usize),
+        };
+        Some(Fm {
+            source,
+            scaling: self.scaling.value(),
+        })
+    }
+}
@@ -0,0 +1,378 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::lut::db_to_gain;
+use crate::spectral::bandpass;
+
+/// Number of formants making up a vowel.
+pub const NUM_FORMANTS: usize = 5;
+
+/// A single resonance of the vocal tract.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Formant {
+    /// Center frequency, in Hz.
+    pub hz: f32,
+    /// Gain at the center frequency, in dB.
+    pub gain_db: f32,
+    /// Bandwidth, in Hz.
+    pub bandwidth: f32,
+}
+
+const fn formant(hz: f32, gain_db: f32, bandwidth: f32) -> Formant {
+    Formant {
+        hz,
+        gain_db,
+        bandwidth,
+    }
+}
+
+/// The formants of one vowel. Custom sets are stored with the plugin state.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FormantSet(pub [Formant; NUM_FORMANTS]);
+
+/// Formants of a bass voice singing A, E, I, O and U, in that order.
+pub const VOWELS: [FormantSet; 5] = [
+    FormantSet([
+        formant(600., 0., 60.),
+        formant(1040., -7., 70.),
+        formant(2250., -9., 110.),
+        formant(2450., -9., 120.),
+        formant(2750., -20., 130.),
+    ]),
+    FormantSet([
+        formant(400., 0., 40.),
+        formant(1620., -12., 80.),
+        formant(2400., -9., 100.),
+        formant(2800., -12., 120.),
+        formant(3100., -18., 120.),
+    ]),
+    FormantSet([
+        formant(250., 0., 60.),
+        formant(1750., -30., 90.),
+        formant(2600., -16., 100.),
+        formant(3050., -22., 120.),
+        formant(3340., -28., 120.),
+    ]),
+    FormantSet([
+        formant(400., 0., 40.),
+        formant(750., -11., 80.),
+        formant(2400., -21., 100.),
+        formant(2600., -20., 120.),
+        formant(2900., -40., 120.),
+    ]),
+    FormantSet([
+        formant(350., 0., 40.),
+        formant(600., -20., 80.),
+        formant(2400., -32., 100.),
+        formant(2675., -28., 120.),
+        formant(2950., -36., 120.),
+    ]),
+];
+
+#[derive(Debug)]
+pub enum FormantSetError {
+    Json(serde_json::Error),
+    /// A formant has a non-finite value, or a frequency or bandwidth that isn't positive. Sets and
+    /// formants are numbered from 0.
+    Invalid { set: usize, formant: usize },
+}
+
+impl fmt::Display for FormantSetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(err) => write!(f, "Invalid JSON formant sets: {err}"),
+            Self::Invalid { set, formant } => write!(
+                f,
+                "Formant {formant} of set {set} needs a positive frequency and bandwidth, and a \
+                 finite gain"
+            ),
+        }
+    }
+}
+
+impl Error for FormantSetError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for FormantSetError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl Formant {
+    /// Whether the formant can be filtered with. Anything else would make the response NaN.
+    fn is_valid(&self) -> bool {
+        let positive = |x: f32| x.is_finite() && x > 0.;
+        positive(self.hz) && positive(self.bandwidth) && self.gain_db.is_finite()
+    }
+}
+
+impl FormantSet {
+    /// Check every formant of `sets`, returning the first invalid one.
+    pub fn validate(sets: &[FormantSet]) -> Result<(), FormantSetError> {
+        for (set, formants) in sets.iter().enumerate() {
+            if let Some(formant) = formants.0.iter().position(|f| !f.is_valid()) {
+                return Err(FormantSetError::Invalid { set, formant });
+            }
+        }
+        Ok(())
+    }
+
+    /// Interpolate between consecutive sets of `sets` at `position`, where integer positions fall
+    /// on the sets themselves. Frequencies and bandwidths are interpolated logarithmically and gains
+    /// in dB, so that the formants glide between vowels.
+    pub fn morph(sets: &[FormantSet], position: f32) -> FormantSet {
+        let position = position.clamp(0., (sets.len() - 1) as f32);
+        let i = (position as usize).min(sets.len().saturating_sub(2));
+        let Some(next) = sets.get(i + 1) else {
+            return sets[i];
+        };
+        let t = position - i as f32;
+        let lerp = |a: f32, b: f32| a + t * (b - a);
+        let geom = |a: f32, b: f32| a * (b / a).powf(t);
+        FormantSet(std::array::from_fn(|k| {
+            let (a, b) = (sets[i].0[k], next.0[k]);
+            formant(
+                geom(a.hz, b.hz),
+                lerp(a.gain_db, b.gain_db),
+                geom(a.bandwidth, b.bandwidth),
+            )
+        }))
+    }
+
+    /// Scale the frequencies and bandwidths of all formants by `ratio`.
+    pub fn scaled(self, ratio: f32) -> Self {
+        Self(self.0.map(|f| formant(f.hz * ratio, f.gain_db, f.bandwidth * ratio)))
+    }
+
+    /// Spectral envelope of the vowel at `hz`, as the sum of one bandpass per formant.
+    pub fn response(&self, hz: f32) -> f32 {
+        self.0
+            .iter()
+            .map(|f| db_to_gain(f.gain_db) * bandpass(hz / f.hz, f.hz / f.bandwidth))
+            .sum()
+    }
+}
+
+/// Vowel envelope imposed on the partials, blended with the unshaped spectrum by `amount`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FormantFilter {
+    pub formants: FormantSet,
+    pub amount: f32,
+}
+
+impl FormantFilter {
+    #[inline]
+    pub fn response(&self, hz: f32) -> f32 {
+        1. + self.amount * (self.formants.response(hz) - 1.)
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormantSource {
+    /// Morph through A, E, I, O and U.
+    Vowels,
+    /// Morph through the custom formant sets saved with the preset.
+    Custom,
+}
+
+#[derive(Params)]
+pub struct FormantParams {
+    #[id = "fmtamt"]
+    pub amount: FloatParam,
+    #[id = "fmtsrc"]
+    pub source: EnumParam<FormantSource>,
+    /// Position within the vowels or custom sets, from 0 to 1.
+    #[id = "vowel"]
+    pub vowel: FloatParam,
+    /// Formant sets of [`FormantSource::Custom`], set through [`FormantParams::set_custom`] or
+    /// [`FormantParams::import_custom`].
+    #[persist = "formant-sets"]
+    pub custom: Arc<RwLock<Vec<FormantSet>>>,
+}
+
+impl fmt::Debug for FormantParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FormantParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for FormantParams {
+    fn default() -> Self {
+        Self {
+            amount: FloatParam::new("Formant Amount", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2)),
+            source: EnumParam::new("Formant Source", FormantSource::Vowels),
+            vowel: FloatParam::new("Vowel", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_value_to_string(Arc::new(|x| {
+                    let position = x * (VOWELS.len() - 1) as f32;
+                    let vowel = |i: usize| ["A", "E", "I", "O", "U"][i];
+                    let i = position.round() as usize;
+                    if (position - i as f32).abs() < 0.05 {
+                        vowel(i).to_string()
+                    } else {
+                        let i = position as usize;
+                        format!("{} > {}", vowel(i), vowel(i + 1))
+                    }
+                })),
+            custom: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+}
+
+impl FormantParams {
+    /// Replace the custom formant sets, keeping the current ones if any formant is invalid. This
+    /// takes their lock for writing, so it must be called from the GUI or another background
+    /// thread.
+    pub fn set_custom(&self, sets: Vec<FormantSet>) -> Result<(), FormantSetError> {
+        FormantSet::validate(&sets)?;
+        *self.custom.write().unwrap_or_else(PoisonError::into_inner) = sets;
+        Ok(())
+    }
+
+    /// Replace the custom formant sets with a JSON array of sets, each an array of
+    /// [`NUM_FORMANTS`] `{"hz": ..., "gain_db": ..., "bandwidth": ...}` objects. The current sets
+    /// are kept if the JSON can't be parsed or any formant is invalid.
+    pub fn import_custom(&self, json: &str) -> Result<(), FormantSetError> {
+        self.set_custom(serde_json::from_str(json)?)
+    }
+
+    pub fn export_custom(&self) -> String {
+        let sets = self.custom.read().unwrap_or_else(PoisonError::into_inner);
+        serde_json::to_string_pretty(&*sets).expect("formant sets always serialize")
+    }
+
+    /// Current formant filter, or `None` when the section is off. Falls back to the vowels when
+    /// there are no custom sets, when they are being edited, or when a saved state holds invalid
+    /// ones.
+    pub fn filter(&self) -> Option<FormantFilter> {
+        let amount = self.amount.value();
+        if amount == 0. {
+            return None;
+        }
+        let vowel = self.vowel.value();
+        let custom = match self.source.value() {
+            FormantSource::Vowels => None,
+            FormantSource::Custom => self
+                .custom
+                .try_read()
+                .ok()
+                .filter(|sets| !sets.is_empty() && FormantSet::validate(sets).is_ok()),
+        };
+        let sets = custom.as_deref().map_or(&VOWELS[..], |sets| &sets[..]);
+        Some(FormantFilter {
+            formants: FormantSet::morph(sets, vowel * (sets.len() - 1) as f32),
+            amount,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nih_plug::prelude::*;
+
+    use super::{FormantParams, FormantSet, FormantSetError, FormantSource, VOWELS};
+
+    #[test]
+    fn peaks_at_formants() {
+        for vowel in VOWELS {
+            let f1 = vowel.0[0].hz;
+            let response = vowel.response(f1);
+            approx::assert_abs_diff_eq!(1., response, epsilon = 0.1);
+            assert!(vowel.response(f1 / 2.) < response / 2.);
+        }
+    }
+
+    #[test]
+    fn morph_goes_through_vowels() {
+        for (i, vowel) in VOWELS.iter().enumerate() {
+            assert_eq!(*vowel, FormantSet::morph(&VOWELS, i as f32));
+        }
+
+        // Halfway between A and E, the first formant is at the geometric mean
+        let ae = FormantSet::morph(&VOWELS, 0.5);
+        approx::assert_relative_eq!((600f32 * 400.).sqrt(), ae.0[0].hz, max_relative = 1e-5);
+        approx::assert_abs_diff_eq!(-9.5, ae.0[1].gain_db, epsilon = 1e-5);
+
+        assert_eq!(VOWELS[4], FormantSet::morph(&VOWELS, 10.));
+        assert_eq!(VOWELS[2], FormantSet::morph(&VOWELS[2..3], 0.5));
+    }
+
+    #[test]
+    fn custom_sets_round_trip_through_json() {
+        let json = serde_json::to_string(&VOWELS[..2]).unwrap();
+        let sets: Vec<FormantSet> = serde_json::from_str(&json).unwrap();
+        assert_eq!(&VOWELS[..2], &sets[..]);
+    }
+
+    #[test]
+    fn filter_uses_custom_sets() {
+        let params = FormantParams {
+            amount: FloatParam::new("Amount", 1., FloatRange::Linear { min: 0., max: 1. }),
+            source: EnumParam::new("Source", FormantSource::Custom),
+            vowel: FloatParam::new("Vowel", 1., FloatRange::Linear { min: 0., max: 1. }),
+            ..FormantParams::default()
+        };
+        // Falls back to the vowels until there are custom sets
+        assert_eq!(VOWELS[4], params.filter().unwrap().formants);
+
+        let custom = [VOWELS[1].scaled(2.), VOWELS[3].scaled(0.5)];
+        params.import_custom(&serde_json::to_string(&custom).unwrap()).unwrap();
+        assert_eq!(custom[1], params.filter().unwrap().formants);
+        assert!(params.import_custom("[[]]").is_err());
+        assert_eq!(&custom[..], &params.custom.read().unwrap()[..]);
+
+        params.set_custom(custom[..1].to_vec()).unwrap();
+        assert_eq!(custom[0], params.filter().unwrap().formants);
+    }
+
+    #[test]
+    fn invalid_sets_are_rejected() {
+        let params = FormantParams::default();
+        params.set_custom(VOWELS[..2].to_vec()).unwrap();
+        for (formant, hz, gain_db, bandwidth) in [
+            (0, 0., 0., 60.),
+            (1, -100., 0., 60.),
+            (2, 600., 0., 0.),
+            (3, 600., f32::NAN, 60.),
+            (4, f32::INFINITY, 0., 60.),
+        ] {
+            let mut set = VOWELS[3];
+            set.0[formant].hz = hz;
+            set.0[formant].gain_db = gain_db;
+            set.0[formant].bandwidth = bandwidth;
+            assert!(matches!(
+                params.set_custom(vec![VOWELS[0], set]),
+                Err(FormantSetError::Invalid { set: 1, formant: f }) if f == formant
+            ));
+        }
+        let mut set = VOWELS[1];
+        set.0[2].bandwidth = -100.;
+        assert!(matches!(
+            params.import_custom(&serde_json::to_string(&[set]).unwrap()),
+            Err(FormantSetError::Invalid { set: 0, formant: 2 })
+        ));
+        assert_eq!(&VOWELS[..2], &params.custom.read().unwrap()[..]);
+
+        // Invalid sets loaded from a saved state are ignored
+        let params = FormantParams {
+            amount: FloatParam::new("Amount", 1., FloatRange::Linear { min: 0., max: 1. }),
+            source: EnumParam::new("Source", FormantSource::Custom),
+            ..FormantParams::default()
+        };
+        *params.custom.write().unwrap() = vec![set];
+        assert_eq!(VOWELS[0], params.filter().unwrap().formants);
+    }
+}
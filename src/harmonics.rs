@@ -0,0 +1,250 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::{Formatter, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// Number of partials of an [`crate::oscillator::Oscillator`], and so the maximum length of a
+/// harmonic table.
+pub const MAX_HARMONICS: usize = 1024;
+
+/// Amplitude and initial phase of one harmonic.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Harmonic {
+    pub amplitude: f32,
+    /// Initial phase, in radians.
+    #[serde(default)]
+    pub phase: f32,
+}
+
+/// A spectrum drawn harmonic by harmonic, the first entry being the fundamental. The table is
+/// stored with the plugin state, and can be exchanged with scripts as JSON or CSV.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct HarmonicTable(pub Vec<Harmonic>);
+
+#[derive(Debug)]
+pub enum HarmonicTableError {
+    Json(serde_json::Error),
+    /// A CSV row couldn't be parsed. Lines are numbered from 1.
+    Csv {
+        line: usize,
+        message: String,
+    },
+    /// The table has more than [`MAX_HARMONICS`] harmonics.
+    TooLong(usize),
+}
+
+impl fmt::Display for HarmonicTableError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(err) => write!(f, "Invalid JSON harmonic table: {err}"),
+            Self::Csv { line, message } => {
+                write!(f, "Invalid CSV harmonic table, line {line}: {message}")
+            }
+            Self::TooLong(len) => write!(
+                f,
+                "Harmonic table has {len} harmonics, at most {MAX_HARMONICS} are supported"
+            ),
+        }
+    }
+}
+
+impl Error for HarmonicTableError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Json(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for HarmonicTableError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl HarmonicTable {
+    /// A table with only the fundamental, which sounds like a sine.
+    pub fn sine() -> Self {
+        Self(vec![Harmonic {
+            amplitude: 1.,
+            phase: 0.,
+        }])
+    }
+
+    /// Harmonic `n`, counting from 1 for the fundamental. Harmonics past the end of the table are
+    /// silent.
+    pub fn get(&self, n: usize) -> Harmonic {
+        n.checked_sub(1)
+            .and_then(|i| self.0.get(i))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Set harmonic `n`, counting from 1 for the fundamental, growing the table if needed.
+    pub fn set(&mut self, n: usize, harmonic: Harmonic) -> Result<(), HarmonicTableError> {
+        if n > MAX_HARMONICS {
+            return Err(HarmonicTableError::TooLong(n));
+        }
+        let Some(i) = n.checked_sub(1) else {
+            return Ok(());
+        };
+        if i >= self.0.len() {
+            self.0.resize(n, Harmonic::default());
+        }
+        self.0[i] = harmonic;
+        Ok(())
+    }
+
+    /// Parse a table in either of the formats below, telling them apart by JSON tables being
+    /// arrays.
+    pub fn parse(text: &str) -> Result<Self, HarmonicTableError> {
+        if text.trim_start().starts_with('[') {
+            Self::from_json(text)
+        } else {
+            Self::from_csv(text)
+        }
+    }
+
+    /// Parse a JSON array of `{"amplitude": ..., "phase": ...}` objects, the phase being optional.
+    pub fn from_json(json: &str) -> Result<Self, HarmonicTableError> {
+        let table: Self = serde_json::from_str(json)?;
+        if table.0.len() > MAX_HARMONICS {
+            return Err(HarmonicTableError::TooLong(table.0.len()));
+        }
+        Ok(table)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("harmonic tables always serialize")
+    }
+
+    /// Parse CSV rows of `harmonic,amplitude[,phase]`, with harmonics counted from 1. Rows can come
+    /// in any order and harmonics can be skipped, in which case they are silent. A header row,
+    /// blank lines and lines starting with `#` are ignored.
+    pub fn from_csv(csv: &str) -> Result<Self, HarmonicTableError> {
+        let mut table = Self::default();
+        let mut first_row = true;
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let error = |message: String| HarmonicTableError::Csv {
+                line: i + 1,
+                message,
+            };
+            let header = std::mem::replace(&mut first_row, false);
+            let Ok(n) = fields[0].parse::<usize>() else {
+                if header {
+                    continue;
+                }
+                return Err(error(format!("invalid harmonic number {:?}", fields[0])));
+            };
+            if !(2..=3).contains(&fields.len()) {
+                return Err(error(format!(
+                    "expected 2 or 3 columns, got {}",
+                    fields.len()
+                )));
+            }
+            if n == 0 {
+                return Err(error("harmonics are numbered from 1".to_string()));
+            }
+            let parse = |s: &str| {
+                s.parse::<f32>()
+                    .map_err(|err| error(format!("invalid number {s:?}: {err}")))
+            };
+            let amplitude = parse(fields[1])?;
+            let phase = fields.get(2).map_or(Ok(0.), |s| parse(s))?;
+            table.set(n, Harmonic { amplitude, phase })?;
+        }
+        Ok(table)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("harmonic,amplitude,phase\n");
+        for (i, harmonic) in self.0.iter().enumerate() {
+            writeln!(csv, "{},{},{}", i + 1, harmonic.amplitude, harmonic.phase).unwrap();
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Harmonic, HarmonicTable, HarmonicTableError};
+
+    fn table() -> HarmonicTable {
+        HarmonicTable(
+            (1..=8)
+                .map(|n| Harmonic {
+                    amplitude: 1. / n as f32,
+                    phase: 0.25 * n as f32,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn round_trips() {
+        let table = table();
+        assert_eq!(table, HarmonicTable::from_json(&table.to_json()).unwrap());
+        assert_eq!(table, HarmonicTable::from_csv(&table.to_csv()).unwrap());
+    }
+
+    #[test]
+    fn sparse_csv() {
+        let csv = "# odd harmonics only\n5, 0.2\n\n1, 1, 0.5\n3,0.333\n";
+        let table = HarmonicTable::from_csv(csv).unwrap();
+        assert_eq!(5, table.0.len());
+        assert_eq!(0.5, table.get(1).phase);
+        assert_eq!(0., table.get(2).amplitude);
+        assert_eq!(0.2, table.get(5).amplitude);
+        assert_eq!(Harmonic::default(), table.get(6));
+
+        assert!(matches!(
+            HarmonicTable::from_csv("1,1\n2,x\n"),
+            Err(HarmonicTableError::Csv { line: 2, .. })
+        ));
+        assert!(matches!(
+            HarmonicTable::from_csv("2000,1\n"),
+            Err(HarmonicTableError::TooLong(2000))
+        ));
+    }
+
+    #[test]
+    fn csv_header_after_comments() {
+        let csv = "# exported spectrum\n\nharmonic,amplitude\n1,1\n2,0.5\n";
+        let table = HarmonicTable::from_csv(csv).unwrap();
+        assert_eq!(0.5, table.get(2).amplitude);
+
+        // Only the first row can be a header
+        assert!(matches!(
+            HarmonicTable::from_csv("1,1\nharmonic,amplitude\n"),
+            Err(HarmonicTableError::Csv { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn parse_detects_the_format() {
+        let table = table();
+        assert_eq!(table, HarmonicTable::parse(&table.to_json()).unwrap());
+        assert_eq!(table, HarmonicTable::parse(&table.to_csv()).unwrap());
+        assert!(matches!(
+            HarmonicTable::parse("[1, 2"),
+            Err(HarmonicTableError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn json_phase_is_optional() {
+        let table =
+            HarmonicTable::from_json(r#"[{"amplitude": 1}, {"amplitude": 0.5, "phase": 1}]"#)
+                .unwrap();
+        assert_eq!(0., table.get(1).phase);
+        assert_eq!(1., table.get(2).phase);
+    }
+}
@@ -0,0 +1,227 @@
+use std::f32::consts::TAU;
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+
+use crate::mod_matrix::{depth_param, ModDestination};
+
+/// The number of LFOs available to each voice.
+pub const NUM_LFOS: usize = 2;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoWaveform {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    #[name = "Sample & Hold"]
+    SampleHold,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LfoMode {
+    /// Every voice runs its own LFO, retriggered on note-on.
+    #[name = "Per Voice"]
+    PerVoice,
+    /// A single free-running LFO is shared by all voices.
+    Global,
+}
+
+#[derive(Params)]
+pub struct LfoParams {
+    #[id = "wave"]
+    pub waveform: EnumParam<LfoWaveform>,
+    #[id = "mode"]
+    pub mode: EnumParam<LfoMode>,
+    #[id = "rate"]
+    pub rate: FloatParam,
+    #[id = "delay"]
+    pub delay: FloatParam,
+    #[id = "fade"]
+    pub fade: FloatParam,
+    #[id = "phase"]
+    pub phase: FloatParam,
+    #[id = "rand"]
+    pub random: FloatParam,
+    #[id = "dest"]
+    pub destination: EnumParam<ModDestination>,
+    #[id = "depth"]
+    pub depth: FloatParam,
+}
+
+impl fmt::Debug for LfoParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LfoParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for LfoParams {
+    fn default() -> Self {
+        Self {
+            waveform: EnumParam::new("Waveform", LfoWaveform::Sine),
+            mode: EnumParam::new("Mode", LfoMode::PerVoice),
+            rate: FloatParam::new(
+                "Rate",
+                2.,
+                FloatRange::Skewed {
+                    min: 0.01,
+                    max: 50.,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            delay: FloatParam::new("Delay", 0., FloatRange::Linear { min: 0., max: 5e3 })
+                .with_unit("ms"),
+            fade: FloatParam::new("Fade In", 0., FloatRange::Linear { min: 0., max: 5e3 })
+                .with_unit("ms"),
+            phase: FloatParam::new("Phase", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2)),
+            random: FloatParam::new(
+                "Phase Randomization",
+                0.,
+                FloatRange::Linear { min: 0., max: 1. },
+            )
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_value_to_string(formatters::v2s_f32_percentage(2)),
+            destination: EnumParam::new("Destination", ModDestination::None),
+            depth: depth_param("Depth"),
+        }
+    }
+}
+
+/// A bipolar low frequency oscillator. The parameters are passed in on every call so that the same
+/// `LfoParams` can drive both the global and the per-voice instances.
+#[derive(Debug, Clone)]
+pub struct Lfo {
+    samplerate: f32,
+    phase: f32,
+    /// Samples elapsed since the last trigger, used for the delay and fade-in.
+    elapsed: u32,
+    /// Current value of the sample & hold waveform, redrawn at the start of every cycle.
+    held: f32,
+    /// Multiplier applied to the rate parameter, set from the modulation matrix.
+    rate_mod: f32,
+    prng: Pcg32,
+}
+
+impl Lfo {
+    pub fn new(samplerate: f32, seed: u64) -> Self {
+        Self {
+            samplerate,
+            phase: 0.,
+            elapsed: 0,
+            held: 0.,
+            rate_mod: 1.,
+            prng: Pcg32::seed_from_u64(seed),
+        }
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.prng = Pcg32::seed_from_u64(seed);
+    }
+
+    /// Scale the LFO's rate by `octaves` octaves.
+    pub fn set_rate_mod(&mut self, octaves: f32) {
+        self.rate_mod = octaves.exp2();
+    }
+
+    /// Restart the LFO from its start phase, offset by a random amount scaled by the phase
+    /// randomization parameter. This also restarts the delay and fade-in.
+    pub fn trigger(&mut self, params: &LfoParams) {
+        let offset = params.random.value() * self.prng.gen::<f32>();
+        self.phase = (params.phase.value() + offset).fract();
+        self.elapsed = 0;
+        self.held = self.prng.gen_range(-1.0..=1.0);
+    }
+
+    pub fn next(&mut self, params: &LfoParams) -> f32 {
+        let delay = (params.delay.value() * self.samplerate / 1e3) as u32;
+        let fade = params.fade.value() * self.samplerate / 1e3;
+        if self.elapsed < delay {
+            self.elapsed += 1;
+            return 0.;
+        }
+        let since_delay = (self.elapsed - delay) as f32;
+        let gain = if since_delay < fade {
+            since_delay / fade
+        } else {
+            1.
+        };
+        self.elapsed = self.elapsed.saturating_add(1);
+
+        let value = match params.waveform.value() {
+            LfoWaveform::Sine => (TAU * self.phase).sin(),
+            LfoWaveform::Triangle => match self.phase {
+                p if p < 0.25 => 4. * p,
+                p if p < 0.75 => 2. - 4. * p,
+                p => 4. * p - 4.,
+            },
+            LfoWaveform::Saw => 2. * (self.phase + 0.5).fract() - 1.,
+            LfoWaveform::Square if self.phase < 0.5 => 1.,
+            LfoWaveform::Square => -1.,
+            LfoWaveform::SampleHold => self.held,
+        };
+
+        self.phase += params.rate.value() * self.rate_mod / self.samplerate;
+        if self.phase >= 1. {
+            self.phase = self.phase.fract();
+            self.held = self.prng.gen_range(-1.0..=1.0);
+        }
+
+        gain * value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_and_fade_in() {
+        const FS: f32 = 1e3;
+        let params = LfoParams {
+            waveform: EnumParam::new("Waveform", LfoWaveform::Square),
+            delay: FloatParam::new("Delay", 10., FloatRange::Linear { min: 0., max: 5e3 }),
+            fade: FloatParam::new("Fade In", 10., FloatRange::Linear { min: 0., max: 5e3 }),
+            ..LfoParams::default()
+        };
+
+        let mut lfo = Lfo::new(FS, 0);
+        lfo.trigger(&params);
+        let out = (0..30).map(|_| lfo.next(&params)).collect::<Vec<_>>();
+        assert!(out[..10].iter().all(|&x| x == 0.));
+        assert!(out[10..20].windows(2).all(|w| w[1].abs() > w[0].abs()));
+        assert!(out[20..].iter().all(|&x| x.abs() == 1.));
+    }
+
+    #[test]
+    fn waveforms_are_bipolar() {
+        const FS: f32 = 1e3;
+        for waveform in [
+            LfoWaveform::Sine,
+            LfoWaveform::Triangle,
+            LfoWaveform::Saw,
+            LfoWaveform::Square,
+            LfoWaveform::SampleHold,
+        ] {
+            let params = LfoParams {
+                waveform: EnumParam::new("Waveform", waveform),
+                rate: FloatParam::new("Rate", 10., FloatRange::Linear { min: 0., max: 50. }),
+                ..LfoParams::default()
+            };
+
+            let mut lfo = Lfo::new(FS, 1337);
+            lfo.trigger(&params);
+            let (min, max) = (0..1000)
+                .map(|_| lfo.next(&params))
+                .fold((f32::MAX, f32::MIN), |(lo, hi), x| (lo.min(x), hi.max(x)));
+            assert!(min >= -1. && max <= 1., "{waveform:?} out of range");
+            assert!(min < -0.5 && max > 0.5, "{waveform:?} does not swing both ways");
+        }
+    }
+}
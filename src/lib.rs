@@ -3,29 +3,58 @@
 #![feature(simd_ffi)]
 #![feature(once_cell)]
 
-use std::sync::Arc;
+use std::{
+    array,
+    sync::{Arc, RwLock},
+};
 
 use nih_plug::prelude::*;
-use rand::Rng;
+use nih_plug::wrapper::state::PluginState;
 use rand_pcg::Pcg32;
 
-use oscillator::Oscillator;
-
 use crate::voice::VoiceParams;
 use crate::{
-    tanh::TanhLut,
-    voice::{Voice, VoiceId},
+    adaa::{Adaa, Antialiasing, SaturationCurve},
+    lfo::{Lfo, NUM_LFOS},
+    lut::db_to_gain,
+    macros::{MacroMapping, MacroParams, NUM_MACROS},
+    math::{publish_nr_stats, NR_STATS},
+    morph::{PartialSet, PartialSets, MAX_MORPH_SETS},
+    oversampling::{OversamplingFactor, Oversampler},
+    voice::{BlockContext, Voice, VoiceId},
 };
 
+mod adaa;
 mod adsr;
+mod diode_ladder;
 mod externs;
+mod filter;
+mod fm;
+mod formant;
+mod harmonics;
+mod lfo;
 mod lpf;
+mod lut;
+mod macros;
 mod math;
+mod migration;
+mod mod_matrix;
+mod modal;
+mod morph;
+mod noise;
+mod nonlinearity;
 mod nr;
 mod oscillator;
+mod oversampling;
+mod phase;
 mod phasor;
-mod tanh;
+mod sallen_key;
+mod spectral;
+mod sub;
+mod svf;
+mod sync;
 mod voice;
+mod wavetable;
 
 /// The number of simultaneous voices for this synth.
 const NUM_VOICES: u32 = 16;
@@ -37,17 +66,35 @@ const MAX_BLOCK_SIZE: usize = 64;
 // `PolyModulation` and `MonoAutomation` events makes it possible to easily link these events to the
 // correct parameter.
 const GAIN_POLY_MOD_ID: u32 = 0;
+const MORPH_POLY_MOD_ID: u32 = 1;
 
 /// A simple polyphonic synthesizer with support for CLAP's polyphonic modulation. See
 /// `NoteEvent::PolyModulation` for another source of information on how to use this.
 struct Addsynth {
     params: Arc<AddsynthParams>,
-    tanh_lut: Arc<TanhLut<true>>,
     /// A pseudo-random number generator. This will always be reseeded with the same seed when the
     /// synth is reset. That way the output is deterministic when rendering multiple times.
     prng: Pcg32,
+    /// Number of samples processed since the synth was reset, used as the time base of the
+    /// free-running partial phases.
+    clock: u64,
     /// The synth's voices. Inactive voices will be set to `None` values.
     voices: [Option<Voice>; NUM_VOICES as usize],
+    /// Partial sets of the voice in the slot with the same index, allocated up front so that
+    /// morphing voices don't allocate on note-on.
+    morph_sets: Box<[PartialSets]>,
+    /// LFOs shared between all voices, used by the LFOs set to global mode.
+    lfos: [Lfo; NUM_LFOS],
+    /// Smoothed mod wheel (CC 1) position.
+    mod_wheel: Smoother<f32>,
+    /// Smoothed channel pressure.
+    aftertouch: Smoother<f32>,
+    /// Oversamplers around the output saturation, for the left and right channels.
+    output_oversampling: [Oversampler; 2],
+    /// Output saturators for the left and right channels.
+    output_saturation: [Adaa; 2],
+    /// Latency last reported to the host.
+    latency: u32,
 }
 
 impl Addsynth {
@@ -60,66 +107,88 @@ impl Addsynth {
     ) -> &mut Voice {
         let samplerate = ctx.transport().sample_rate;
         let hz = util::midi_note_to_freq(id.note);
-        let mut voice = Voice::new(
-            Oscillator::sine(samplerate, hz),
-            id,
-            velocity,
-            self.params.voice.clone(),
-        );
-        voice.oscillator.phase_offset = self.prng.gen();
-
-        return match self.voices.iter().position(|v| v.is_none()) {
-            Some(free_voice_id) => {
-                self.voices[free_voice_id] = Some(voice);
-                self.voices[free_voice_id].as_mut().unwrap()
-            }
+        let mut osc = self
+            .params
+            .voice
+            .oscillator(self.params.voice.osc.value(), samplerate, hz);
+        self.params
+            .voice
+            .phase
+            .init(&mut osc, &mut self.prng, self.clock);
+
+        let slot = match self.voices.iter().position(|v| v.is_none()) {
+            Some(free_voice_id) => free_voice_id,
             None => {
-                let oldest = unsafe {
+                let (oldest_id, oldest) = unsafe {
                     self.voices
-                        .iter_mut()
-                        .min_by_key(|voice| voice.as_ref().unwrap_unchecked().id())
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, voice)| voice.as_ref().unwrap_unchecked().id())
                         .unwrap_unchecked()
                 };
-                {
-                    let oldest = oldest.as_ref().unwrap();
-                    ctx.send_event(NoteEvent::VoiceTerminated {
-                        timing: sample_offset,
-                        voice_id: Some(oldest.voice_id()),
-                        channel: oldest.channel(),
-                        note: oldest.note(),
-                    });
-                }
-                *oldest = Some(voice);
-                oldest.as_mut().unwrap()
+                let oldest = oldest.as_ref().unwrap();
+                ctx.send_event(NoteEvent::VoiceTerminated {
+                    timing: sample_offset,
+                    voice_id: Some(oldest.voice_id()),
+                    channel: oldest.channel(),
+                    note: oldest.note(),
+                });
+                oldest_id
             }
         };
+        let voice = Voice::new(
+            osc,
+            id,
+            velocity,
+            self.params.voice.clone(),
+            &mut self.prng,
+            self.clock,
+            &mut self.morph_sets[slot],
+        );
+        self.voices[slot].insert(voice)
     }
 }
 
-#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
-enum OscillatorType {
-    Sine,
-    Triangle,
-    Saw,
-    Square,
-}
-
 #[derive(Params)]
 struct AddsynthParams {
     #[nested(id_prefix = "voice", group = "Voice")]
     voice: Arc<VoiceParams>,
     #[id = "out"]
     out_drive: FloatParam,
+    #[id = "outsat"]
+    out_curve: EnumParam<SaturationCurve>,
+    /// Antiderivative anti-aliasing of the output saturation, a cheaper alternative to
+    /// oversampling that can also be combined with it.
+    #[id = "outaa"]
+    out_antialiasing: EnumParam<Antialiasing>,
+    /// Oversampling of the voice filters and of the output saturation.
+    #[id = "os"]
+    oversampling: EnumParam<OversamplingFactor>,
+    #[nested(array, group = "Macro")]
+    macros: [MacroParams; NUM_MACROS],
+    /// Routings from the macros to the modulation destinations. These are not parameters, and can
+    /// be edited programmatically or from the editor.
+    #[persist = "macro-mappings"]
+    macro_mappings: Arc<RwLock<Vec<MacroMapping>>>,
 }
 
 impl Default for Addsynth {
     fn default() -> Self {
         Self {
             params: Arc::new(AddsynthParams::default()),
-            tanh_lut: Arc::new(TanhLut::new()),
             prng: Pcg32::new(420, 1337),
+            clock: 0,
             // `[None; N]` requires the `Some(T)` to be `Copy`able
             voices: [0; NUM_VOICES as usize].map(|_| None),
+            morph_sets: vec![[PartialSet::default(); MAX_MORPH_SETS]; NUM_VOICES as usize]
+                .into_boxed_slice(),
+            lfos: array::from_fn(|i| Lfo::new(44.1e3, i as u64)),
+            mod_wheel: Smoother::new(SmoothingStyle::Linear(10.)),
+            aftertouch: Smoother::new(SmoothingStyle::Linear(10.)),
+            output_oversampling: [0; 2].map(|_| Oversampler::new(OversamplingFactor::X1)),
+            output_saturation: [0; 2]
+                .map(|_| Adaa::new(SaturationCurve::SoftClip, Antialiasing::Off)),
+            latency: 0,
         }
     }
 }
@@ -140,6 +209,11 @@ impl Default for AddsynthParams {
             )
             .with_unit("dB")
             .with_smoother(SmoothingStyle::Exponential(50.)),
+            out_curve: EnumParam::new("Output Saturation", SaturationCurve::SoftClip),
+            out_antialiasing: EnumParam::new("Output Anti-aliasing", Antialiasing::Off),
+            oversampling: EnumParam::new("Oversampling", OversamplingFactor::X1),
+            macros: Default::default(),
+            macro_mappings: Arc::new(RwLock::new(Vec::new())),
         }
     }
 }
@@ -149,13 +223,13 @@ impl Plugin for Addsynth {
     const VENDOR: &'static str = "SolarLiner";
     const URL: &'static str = "https://youtu.be/dQw4w9WgXcQ";
     const EMAIL: &'static str = "solarliner@gmail.com";
-    const VERSION: &'static str = "0.0.1";
+    const VERSION: &'static str = "0.0.2";
 
     const DEFAULT_INPUT_CHANNELS: u32 = 0;
 
     const DEFAULT_OUTPUT_CHANNELS: u32 = 2;
-    // We won't need any MIDI CCs here, we just want notes and polyphonic modulation
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    // MIDI CCs are needed for the mod wheel and aftertouch modulation sources
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
     type BackgroundTask = ();
@@ -164,14 +238,57 @@ impl Plugin for Addsynth {
         self.params.clone()
     }
 
+    fn filter_state(state: &mut PluginState) {
+        migration::migrate(state);
+    }
+
+    fn initialize(
+        &mut self,
+        _bus_config: &BusConfig,
+        buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        lut::init();
+        self.lfos = array::from_fn(|i| Lfo::new(buffer_config.sample_rate, i as u64));
+        true
+    }
+
     // If the synth as a variable number of voices, you will need to call
     // `context.set_current_voice_capacity()` in `initialize()` and in `process()` (when the
     // capacity changes) to inform the host about this.
     fn reset(&mut self) {
         // This ensures the output is at least somewhat deterministic when rendering to audio
         self.prng = Pcg32::new(420, 1337);
+        self.clock = 0;
 
         self.voices.fill(None);
+        self.mod_wheel.reset(0.);
+        self.aftertouch.reset(0.);
+        for oversampler in &mut self.output_oversampling {
+            oversampler.reset();
+        }
+        for saturation in &mut self.output_saturation {
+            saturation.reset();
+        }
+        for (i, (lfo, params)) in self
+            .lfos
+            .iter_mut()
+            .zip(self.params.voice.lfo.iter())
+            .enumerate()
+        {
+            lfo.reseed(i as u64);
+            lfo.trigger(params);
+        }
+    }
+
+    fn deactivate(&mut self) {
+        let stats = NR_STATS.snapshot();
+        nih_log!(
+            "Filter solver: {} solves, {} iterations, {} failures",
+            stats.solves,
+            stats.iterations,
+            stats.failures
+        );
     }
 
     fn process(
@@ -180,7 +297,6 @@ impl Plugin for Addsynth {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        eprintln!("Addsynth::process");
         // NIH-plug has a block-splitting adapter for `Buffer`. While this works great for effect
         // plugins, for polyphonic synths the block size should be `min(MAX_BLOCK_SIZE,
         // num_remaining_samples, next_event_idx - block_start_idx)`. Because blocks also need to be
@@ -190,6 +306,18 @@ impl Plugin for Addsynth {
         let sample_rate = context.transport().sample_rate;
         let output = buffer.as_slice();
 
+        // The voice filters and the output stage are oversampled one after the other. The
+        // anti-aliasing delay applies at the oversampled rate and is only a fraction of a sample,
+        // so that part is rounded.
+        let oversampling = self.params.oversampling.value();
+        let antialiasing = self.params.out_antialiasing.value();
+        let latency = 2 * oversampling.latency() as u32
+            + (antialiasing.latency() / oversampling.ratio() as f32).round() as u32;
+        if latency != self.latency {
+            context.set_latency_samples(latency);
+            self.latency = latency;
+        }
+
         let mut next_event = context.next_event();
         let mut block_start: usize = 0;
         let mut block_end: usize = MAX_BLOCK_SIZE.min(num_samples);
@@ -237,6 +365,49 @@ impl Plugin for Addsynth {
                             } => {
                                 self.choke_voices(context, timing, voice_id, channel, note);
                             }
+                            NoteEvent::MidiCC { cc: 1, value, .. } => {
+                                self.mod_wheel.set_target(sample_rate, value);
+                            }
+                            NoteEvent::MidiChannelPressure { pressure, .. } => {
+                                self.aftertouch.set_target(sample_rate, pressure);
+                            }
+                            NoteEvent::PolyPressure {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                pressure,
+                            } => {
+                                for voice in self
+                                    .voices
+                                    .iter_mut()
+                                    .filter_map(|v| v.as_mut())
+                                    .filter(|v| v.matches(voice_id, channel, note))
+                                {
+                                    voice.set_pressure(pressure);
+                                }
+                            }
+                            NoteEvent::PolyModulation {
+                                timing: _,
+                                voice_id,
+                                poly_modulation_id: MORPH_POLY_MOD_ID,
+                                normalized_offset,
+                            } => {
+                                if let Some(voice_idx) = self.get_voice_idx(voice_id) {
+                                    let voice = self.voices[voice_idx].as_mut().unwrap();
+                                    // Voices started in this block start at the modulated value
+                                    voice.set_morph_mod(normalized_offset, voice.id() >= next_id);
+                                }
+                            }
+                            NoteEvent::MonoAutomation {
+                                timing: _,
+                                poly_modulation_id: MORPH_POLY_MOD_ID,
+                                normalized_value,
+                            } => {
+                                for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
+                                    voice.update_morph(normalized_value);
+                                }
+                            }
                             _ => (),
                         };
 
@@ -264,14 +435,44 @@ impl Plugin for Addsynth {
             // parameters. The `voice_*` arrays are scratch arrays that an individual voice can use.
             let block_len = block_end - block_start;
 
-            eprintln!("About to process voices");
-            for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
-                for (value_idx, sample_idx) in (block_start..block_end).enumerate() {
-                    let sample = voice.next_sample();
+            let mut block = BlockContext::default();
+            block.render_params(&self.params.voice, block_len);
+            block.oversampling = oversampling;
+            self.mod_wheel.next_block(&mut block.mod_wheel, block_len);
+            self.aftertouch.next_block(&mut block.aftertouch, block_len);
+            // The global LFOs always run so that they keep their phase when switching modes
+            for ((buffer, lfo), params) in block
+                .lfos
+                .iter_mut()
+                .zip(self.lfos.iter_mut())
+                .zip(self.params.voice.lfo.iter())
+            {
+                buffer[..block_len].fill_with(|| lfo.next(params));
+            }
 
-                    output[0][sample_idx] += sample;
-                }
+            let mut macro_values = [[0.; MAX_BLOCK_SIZE]; NUM_MACROS];
+            for (values, params) in macro_values.iter_mut().zip(self.params.macros.iter()) {
+                params.value.smoothed.next_block(values, block_len);
             }
+            // Never wait on the editor from the audio thread, if the mappings are being edited
+            // they'll only be skipped for this block
+            if let Ok(mappings) = self.params.macro_mappings.try_read() {
+                macros::render(&mappings, &macro_values, block_len, &mut block.macros);
+            }
+
+            let (left, right) = output.split_at_mut(1);
+            for (voice, morph_sets) in self.voices.iter_mut().zip(self.morph_sets.iter()) {
+                let Some(voice) = voice else {
+                    continue;
+                };
+                voice.process_block(
+                    &block,
+                    morph_sets,
+                    &mut left[0][block_start..block_end],
+                    &mut right[0][block_start..block_end],
+                );
+            }
+            publish_nr_stats();
 
             // Terminate voices whose release period has fully ended. This could be done as part of
             // the previous loop but this is simpler.
@@ -293,16 +494,24 @@ impl Plugin for Addsynth {
             }
 
             // And then just keep processing blocks until we've run out of buffer to fill
+            self.clock += (block_end - block_start) as u64;
             block_start = block_end;
             block_end = (block_start + MAX_BLOCK_SIZE).min(num_samples);
         }
 
         let (l,rest) = output.split_first_mut().unwrap();
         let (r,_) = rest.split_first_mut().unwrap();
+        let [os_l, os_r] = &mut self.output_oversampling;
+        os_l.set_factor(oversampling);
+        os_r.set_factor(oversampling);
+        let [sat_l, sat_r] = &mut self.output_saturation;
+        let curve = self.params.out_curve.value();
+        sat_l.set(curve, antialiasing);
+        sat_r.set(curve, antialiasing);
         for (l, r) in l.iter_mut().zip(r.iter_mut()) {
-            let amp = util::db_to_gain(self.params.out_drive.smoothed.next());
-            *l = sat(amp * *l) / amp.min(1.);
-            *r = *l;
+            let amp = db_to_gain(self.params.out_drive.smoothed.next());
+            *l = os_l.process(amp * *l, |x| sat_l.process(x)) / amp.min(1.);
+            *r = os_r.process(amp * *r, |x| sat_r.process(x)) / amp.min(1.);
         }
         ProcessStatus::Normal
     }
@@ -375,6 +584,11 @@ impl ClapPlugin for Addsynth {
         ClapFeature::Synthesizer,
         ClapFeature::Stereo,
     ];
+
+    const CLAP_POLY_MODULATION_CONFIG: Option<PolyModulationConfig> = Some(PolyModulationConfig {
+        max_voice_capacity: NUM_VOICES,
+        supports_overlapping_voices: true,
+    });
 }
 
 impl Vst3Plugin for Addsynth {
@@ -388,8 +602,3 @@ impl Vst3Plugin for Addsynth {
 nih_export_clap!(Addsynth);
 nih_export_vst3!(Addsynth);
 
-const DIODE_PARAM: f32 = 0.2577819;
-#[inline]
-fn sat(x: f32) -> f32 {
-    x / (DIODE_PARAM + x.abs())
-}
@@ -1,10 +1,11 @@
-use std::f32::consts::PI;
+use std::f32::consts::{PI, TAU};
+use std::f64::consts::{FRAC_PI_4, SQRT_2};
 
 use nalgebra::{SMatrix, SVector};
-use nih_plug::nih_log;
-use num_complex::ComplexFloat;
+use nih_plug::prelude::Enum;
 
-use crate::math::{nr_step, ScalarField};
+use crate::math::{nr_solve, ScalarField};
+use crate::nonlinearity::Nonlinearity;
 
 #[derive(Debug, Copy, Clone)]
 pub struct LP1 {
@@ -72,6 +73,64 @@ impl<const N: usize> LP<N> {
 
 type Y = SVector<f32, 4>;
 
+/// Iteration limit and relative squared step length tolerance of the per-sample Newton-Raphson
+/// solves, see [`nr_solve`].
+pub(crate) const NR_MAX_ITER: usize = 8;
+pub(crate) const NR_TOL: f32 = 1e-8;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterMode {
+    #[name = "LP 6 dB"]
+    Lp1,
+    #[name = "LP 12 dB"]
+    Lp2,
+    #[name = "LP 18 dB"]
+    Lp3,
+    #[name = "LP 24 dB"]
+    Lp4,
+    #[name = "HP 6 dB"]
+    Hp1,
+    #[name = "HP 12 dB"]
+    Hp2,
+    #[name = "HP 18 dB"]
+    Hp3,
+    #[name = "HP 24 dB"]
+    Hp4,
+    #[name = "BP 12 dB"]
+    Bp2,
+    #[name = "BP 24 dB"]
+    Bp4,
+    Notch,
+}
+
+impl FilterMode {
+    /// Coefficients of the ladder's input (after the resonance feedback is subtracted) and of the
+    /// four stage outputs. With each stage being a one-pole lowpass `G`, highpass responses come
+    /// from expanding `(1 - G)^n`, and the bandpasses from `G^n (1 - G)^n` normalized to unity
+    /// gain at the cutoff.
+    #[rustfmt::skip]
+    const fn mix(self) -> [f32; 5] {
+        match self {
+            Self::Lp1 => [0., 1., 0., 0., 0.],
+            Self::Lp2 => [0., 0., 1., 0., 0.],
+            Self::Lp3 => [0., 0., 0., 1., 0.],
+            Self::Lp4 => [0., 0., 0., 0., 1.],
+            Self::Hp1 => [1., -1., 0., 0., 0.],
+            Self::Hp2 => [1., -2., 1., 0., 0.],
+            Self::Hp3 => [1., -3., 3., -1., 0.],
+            Self::Hp4 => [1., -4., 6., -4., 1.],
+            Self::Bp2 => [0., 2., -2., 0., 0.],
+            Self::Bp4 => [0., 0., 4., -8., 4.],
+            Self::Notch => [1., -2., 2., 0., 0.],
+        }
+    }
+
+    /// Whether the mode's passband includes DC, and as such gets attenuated by the resonance.
+    const fn is_lowpass(self) -> bool {
+        matches!(self, Self::Lp1 | Self::Lp2 | Self::Lp3 | Self::Lp4)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Ladder {
     samplerate: f32,
@@ -80,48 +139,101 @@ pub struct Ladder {
     y: Y,
     k: f32,
     fb: f32,
+    mode: FilterMode,
+    compensation: f32,
+    nonlinearity: Nonlinearity,
 }
 
 impl Ladder {
     pub fn new(samplerate: f32, fc: f32, q: f32) -> Self {
-        Self {
+        let mut this = Self {
             samplerate,
             u: Y::zeros(),
-            g: PI * fc / samplerate,
+            g: 0.,
             y: Y::zeros(),
             k: q,
             fb: 0.,
-        }
+            mode: FilterMode::Lp4,
+            compensation: 0.,
+            nonlinearity: Nonlinearity::Tanh,
+        };
+        this.set_fc(fc);
+        this
+    }
+
+    /// Change the sample rate, keeping the state of the filter.
+    pub fn set_samplerate(&mut self, samplerate: f32, fc: f32) {
+        self.samplerate = samplerate;
+        self.set_fc(fc);
     }
 
     pub fn set_fc(&mut self, fc: f32) {
-        self.g = PI * fc.min(self.samplerate) / self.samplerate;
+        // Each stage is a backward Euler discretization of `y' = wc (x - y)`
+        self.g = TAU * fc.min(self.samplerate) / self.samplerate;
     }
 
     pub fn set_resonance(&mut self, q: f32) {
         self.k = q;
     }
 
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_nonlinearity(&mut self, nonlinearity: Nonlinearity) {
+        self.nonlinearity = nonlinearity;
+    }
+
+    /// Amount of passband gain compensation, between 0 and 1. The resonance feedback lowers the DC
+    /// gain of the lowpass modes to `1 / (1 + k)`; at full compensation the output is scaled back
+    /// up by `1 + k` so that the passband level stays put as the resonance increases.
+    pub fn set_compensation(&mut self, amount: f32) {
+        self.compensation = amount;
+    }
+
+    /// Resonance above which the filter self-oscillates at the current cutoff, or `None` if the
+    /// cutoff is too high for the filter to oscillate at all.
+    ///
+    /// The loop oscillates at the frequency where each backward Euler stage `g / (1 + g - z^-1)`
+    /// shifts the phase by 45°, which gives a threshold of 4 at low cutoffs, rising with the
+    /// cutoff. Past `g = sqrt(2) - 1` the stages can no longer reach that phase shift.
+    pub fn self_oscillation_threshold(&self) -> Option<f32> {
+        // The phase condition is badly conditioned at low cutoffs, hence the double precision
+        let g = self.g as f64;
+        let s = (1. + g) / SQRT_2;
+        if s > 1. {
+            return None;
+        }
+        let w = s.asin() - FRAC_PI_4;
+        Some((4. * (w.sin() / g).powi(4)) as f32)
+    }
+
+    /// Solve for the stage outputs at the next sample, returning whether the solver converged.
     #[inline(always)]
-    pub fn process_sample(&mut self, x: f32) -> f32 {
+    fn solve(&mut self, x: f32) -> bool {
         let phi = Phi {
             g: self.g,
             k: self.k,
             s: self.y,
             x,
+            nl: self.nonlinearity,
         };
-        for i in 0..4 {
-            let Some(step) = nr_step(&phi, &self.y) else {
-                break;
-            };
-            self.y -= step;
-            if step.magnitude_squared() < 1e-4 {
-                // nih_log!("Converged after {i} iterations (mag. {} < 1e-4)", step.magnitude_squared());
-                break;
-            }
-        }
+        let converged = nr_solve(&phi, &mut self.y, NR_MAX_ITER, NR_TOL);
         self.u = phi.eval_u(&self.y);
-        self.y[3]
+        converged
+    }
+
+    #[inline(always)]
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        self.solve(x);
+
+        let [c, y @ ..] = self.mode.mix();
+        let out = c * (x - self.k * self.y[3]) + Y::from(y).dot(&self.y);
+        if self.mode.is_lowpass() {
+            out * (1. + self.compensation * self.k)
+        } else {
+            out
+        }
     }
 }
 
@@ -130,19 +242,7 @@ struct Phi {
     g: f32,
     k: f32,
     s: Y,
-}
-
-const DIODE_PARAM: f32 = 0.2577819;
-#[inline]
-fn sat(x: f32) -> f32 {
-    x.tanh()
-    // x/(DIODE_PARAM+x.abs())
-}
-
-#[inline]
-fn satd(x: f32) -> f32 {
-    1. - x.tanh().powi(2)
-    // DIODE_PARAM / (DIODE_PARAM + x.abs().powi(2))
+    nl: Nonlinearity,
 }
 
 impl Phi {
@@ -158,7 +258,7 @@ impl Phi {
 
     #[inline(always)]
     fn eval_u(&self, y: &Y) -> Y {
-        self.v(y).map(sat) * self.g
+        self.v(y).map(|v| self.nl.f(v)) * self.g
     }
 }
 
@@ -172,7 +272,7 @@ impl ScalarField<f32, 4> for Phi {
     #[rustfmt::skip]
     fn jacobian(&self, y: &SVector<f32, 4>) -> SMatrix<f32, 4, 4> {
         let v = self.v(y);
-        let v = v.map(satd);
+        let v = v.map(|v| self.nl.df(v));
         SMatrix::<_, 4, 4>::new(
             // Row 1
             -v[0], 0., 0., -self.k * v[0],
@@ -190,7 +290,138 @@ impl ScalarField<f32, 4> for Phi {
 mod tests {
     use std::{fs::File, io::Write};
 
-    use crate::lpf::Ladder;
+    use crate::filter::sine_gain_db;
+    use crate::lpf::{FilterMode, Ladder};
+    use crate::nonlinearity::Nonlinearity;
+
+    const FS: f32 = 48e3;
+    const FC: f32 = 1e3;
+
+    /// Steady-state gain of the unresonant ladder at `freq`, in dB.
+    fn gain_db(mode: FilterMode, freq: f32) -> f32 {
+        let mut filter = Ladder::new(FS, FC, 0.);
+        filter.set_mode(mode);
+        response_db(filter, freq)
+    }
+
+    fn response_db(mut filter: Ladder, freq: f32) -> f32 {
+        sine_gain_db(FS, freq, |x| filter.process_sample(x))
+    }
+
+    #[test]
+    fn lowpass_slopes() {
+        for (order, mode) in [FilterMode::Lp1, FilterMode::Lp2, FilterMode::Lp3, FilterMode::Lp4]
+            .into_iter()
+            .enumerate()
+        {
+            let order = order as f32 + 1.;
+            assert!(gain_db(mode, 62.5).abs() < 0.5, "{mode:?} passband");
+            let slope = gain_db(mode, 4e3) - gain_db(mode, 8e3);
+            approx::assert_abs_diff_eq!(6. * order, slope, epsilon = 1.5 * order);
+        }
+    }
+
+    #[test]
+    fn highpass_slopes() {
+        for (order, mode) in [FilterMode::Hp1, FilterMode::Hp2, FilterMode::Hp3, FilterMode::Hp4]
+            .into_iter()
+            .enumerate()
+        {
+            let order = order as f32 + 1.;
+            assert!(gain_db(mode, 8e3).abs() < 3., "{mode:?} passband");
+            let slope = gain_db(mode, 125.) - gain_db(mode, 62.5);
+            approx::assert_abs_diff_eq!(6. * order, slope, epsilon = 0.5 * order);
+        }
+    }
+
+    #[test]
+    fn bandpass_peaks_at_cutoff() {
+        for mode in [FilterMode::Bp2, FilterMode::Bp4] {
+            let center = gain_db(mode, FC);
+            assert!(center.abs() < 1.5, "{mode:?} center gain {center} dB");
+            assert!(gain_db(mode, FC / 8.) < center - 9.);
+            assert!(gain_db(mode, FC * 8.) < center - 9.);
+        }
+    }
+
+    #[test]
+    fn resonance_compensation_keeps_passband() {
+        // Staying under the self-oscillation threshold at 1 kHz
+        for q in [0., 1., 2., 4.] {
+            let mut filter = Ladder::new(FS, FC, q);
+            filter.set_compensation(1.);
+            approx::assert_abs_diff_eq!(0., response_db(filter, 30.), epsilon = 0.5);
+
+            let uncompensated = response_db(Ladder::new(FS, FC, q), 30.);
+            approx::assert_abs_diff_eq!(-20. * (1. + q).log10(), uncompensated, epsilon = 0.5);
+        }
+    }
+
+    /// Peak of the output over the last 100 ms of one second, after an initial impulse.
+    fn ringing(q: f32) -> f32 {
+        let mut filter = Ladder::new(FS, FC, q);
+        let mut peak = 0f32;
+        for i in 0..FS as usize {
+            let y = filter.process_sample(if i == 0 { 0.1 } else { 0. });
+            if i as f32 >= 0.9 * FS {
+                peak = peak.max(y.abs());
+            }
+        }
+        peak
+    }
+
+    #[test]
+    fn self_oscillation_threshold() {
+        assert!(Ladder::new(FS, 20e3, 0.).self_oscillation_threshold().is_none());
+        approx::assert_abs_diff_eq!(
+            4.,
+            Ladder::new(FS, 20., 0.).self_oscillation_threshold().unwrap(),
+            epsilon = 0.05
+        );
+
+        let threshold = Ladder::new(FS, FC, 0.).self_oscillation_threshold().unwrap();
+        assert!(threshold > 4. && threshold < 16.);
+        assert!(ringing(0.95 * threshold) < 1e-4);
+        assert!(ringing(1.05 * threshold) > 1e-2);
+    }
+
+    #[test]
+    fn newton_converges_over_drive_and_resonance() {
+        for nl in [
+            Nonlinearity::Tanh,
+            Nonlinearity::Diode,
+            Nonlinearity::Transistor,
+            Nonlinearity::Linear,
+        ] {
+            for drive_db in [0., 12., 24., 36.] {
+                // Past the self-oscillation threshold the linear filter blows up, as it should
+                let resonances: &[f32] = match nl {
+                    Nonlinearity::Linear => &[0., 2., 4.],
+                    _ => &[0., 2., 4., 8., 16.],
+                };
+                for &q in resonances {
+                    let drive = 10f32.powf(drive_db / 20.);
+                    let mut filter = Ladder::new(FS, FC, q);
+                    filter.set_nonlinearity(nl);
+                    for i in 0..4800 {
+                        // 110 Hz sawtooth
+                        let x = drive * (2. * (i as f32 * 110. / FS).fract() - 1.);
+                        assert!(
+                            filter.solve(x),
+                            "{nl:?} at {drive_db} dB, q = {q}: no convergence at sample {i}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn notch_rejects_cutoff() {
+        assert!(gain_db(FilterMode::Notch, FC) < -20.);
+        assert!(gain_db(FilterMode::Notch, 62.5).abs() < 0.5);
+        assert!(gain_db(FilterMode::Notch, 8e3).abs() < 3.);
+    }
 
     #[test]
     fn phi_nr() {
@@ -0,0 +1,254 @@
+use std::ops::RangeInclusive;
+use std::simd::{f32x8, usizex8, Simd, SimdFloat, SimdOrd};
+use std::sync::LazyLock;
+
+/// Table of `tanh`, used by the filter nonlinearities. Past the end of the range `tanh` is within
+/// 1e-7 of ±1, so clamping there is as good as computing it.
+pub static TANH: LazyLock<Lut> =
+    LazyLock::new(|| Lut::odd(f32::tanh, 8., 1024, Interpolation::Cubic));
+
+/// Table of decibel to linear gain conversions, with a resolution of 0.1 dB.
+static DB_TO_GAIN: LazyLock<Lut> = LazyLock::new(|| {
+    Lut::new(
+        |db| 10f32.powf(db / 20.),
+        -96.0..=96.,
+        1921,
+        Interpolation::Cubic,
+    )
+});
+
+/// Build the shared tables. They are otherwise built on first use, which would allocate on the
+/// audio thread, so this is called from `Plugin::initialize()`.
+pub fn init() {
+    LazyLock::force(&TANH);
+    LazyLock::force(&DB_TO_GAIN);
+}
+
+/// Convert decibels to linear gain through a lookup table. Values are clamped to ±96 dB.
+#[inline]
+pub fn db_to_gain(db: f32) -> f32 {
+    DB_TO_GAIN.get(db)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Nearest,
+    Linear,
+    /// Cubic Hermite (Catmull-Rom) interpolation, using the two entries on either side.
+    Cubic,
+}
+
+/// Lookup table of a function sampled at evenly spaced points over a range. Inputs outside of the
+/// range are clamped to it.
+#[derive(Debug, Clone)]
+pub struct Lut {
+    /// Function values, with one extra entry before the range and two after it so that cubic
+    /// interpolation never reads out of bounds.
+    values: Vec<f32>,
+    min: f32,
+    /// Number of entries per unit of input.
+    scale: f32,
+    /// Highest valid position within the table, relative to the first entry of the range.
+    last: f32,
+    interpolation: Interpolation,
+    /// Whether the function is odd and only the positive half of its range was tabulated.
+    odd: bool,
+}
+
+impl Lut {
+    /// Tabulate `f` at `size` points over `range`.
+    pub fn new(
+        f: impl Fn(f32) -> f32,
+        range: RangeInclusive<f32>,
+        size: usize,
+        interpolation: Interpolation,
+    ) -> Self {
+        assert!(size >= 2, "a lookup table needs at least 2 entries");
+        let (min, max) = range.into_inner();
+        let step = (max - min) / (size - 1) as f32;
+        let values = (-1..=size as isize + 1)
+            .map(|i| f(min + i as f32 * step))
+            .collect();
+        Self {
+            values,
+            min,
+            scale: step.recip(),
+            last: (size - 1) as f32,
+            interpolation,
+            odd: false,
+        }
+    }
+
+    /// Tabulate the odd function `f` over `[-max, max]`, only storing its positive half so that the
+    /// same size gives twice the resolution.
+    pub fn odd(
+        f: impl Fn(f32) -> f32,
+        max: f32,
+        size: usize,
+        interpolation: Interpolation,
+    ) -> Self {
+        Self {
+            odd: true,
+            ..Self::new(f, 0.0..=max, size, interpolation)
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, x: f32) -> f32 {
+        let t = if self.odd { x.abs() } else { x };
+        let t = ((t - self.min) * self.scale).clamp(0., self.last);
+        let y = match self.interpolation {
+            Interpolation::Nearest => self.values[t.round() as usize + 1],
+            Interpolation::Linear => {
+                let i = (t as usize).min(self.values.len() - 4);
+                let f = t - i as f32;
+                let [y0, y1] = [self.values[i + 1], self.values[i + 2]];
+                y0 + f * (y1 - y0)
+            }
+            Interpolation::Cubic => {
+                let i = (t as usize).min(self.values.len() - 4);
+                let f = t - i as f32;
+                let p = &self.values[i..i + 4];
+                hermite(p[0], p[1], p[2], p[3], f)
+            }
+        };
+        if self.odd {
+            y.copysign(x)
+        } else {
+            y
+        }
+    }
+
+    /// Look up 8 values at once.
+    #[inline]
+    pub fn get_simd(&self, x: f32x8) -> f32x8 {
+        let t = if self.odd { x.abs() } else { x };
+        let t = ((t - f32x8::splat(self.min)) * f32x8::splat(self.scale))
+            .simd_max(f32x8::splat(0.))
+            .simd_min(f32x8::splat(self.last));
+        let gather = |i: usizex8| Simd::gather_or_default(&self.values, i);
+        let y = match self.interpolation {
+            Interpolation::Nearest => {
+                gather((t + f32x8::splat(0.5)).cast::<usize>() + usizex8::splat(1))
+            }
+            Interpolation::Linear | Interpolation::Cubic => {
+                let i = t
+                    .cast::<usize>()
+                    .simd_min(usizex8::splat(self.values.len() - 4));
+                let f = t - i.cast::<f32>();
+                let one = usizex8::splat(1);
+                let [y0, y1] = [gather(i + one), gather(i + one + one)];
+                if self.interpolation == Interpolation::Linear {
+                    y0 + f * (y1 - y0)
+                } else {
+                    let [ym, y2] = [gather(i), gather(i + one + one + one)];
+                    hermite_simd(ym, y0, y1, y2, f)
+                }
+            }
+        };
+        if self.odd {
+            y.copysign(x)
+        } else {
+            y
+        }
+    }
+}
+
+/// Catmull-Rom interpolation between `y0` and `y1` at `f`, given the neighbouring points `ym` and
+/// `y2`.
+#[inline(always)]
+fn hermite(ym: f32, y0: f32, y1: f32, y2: f32, f: f32) -> f32 {
+    let c1 = 0.5 * (y1 - ym);
+    let c2 = ym - 2.5 * y0 + 2. * y1 - 0.5 * y2;
+    let c3 = 0.5 * (y2 - ym) + 1.5 * (y0 - y1);
+    ((c3 * f + c2) * f + c1) * f + y0
+}
+
+/// SIMD version of [`hermite`].
+#[inline(always)]
+fn hermite_simd(ym: f32x8, y0: f32x8, y1: f32x8, y2: f32x8, f: f32x8) -> f32x8 {
+    let half = f32x8::splat(0.5);
+    let c1 = half * (y1 - ym);
+    let c2 = ym - f32x8::splat(2.5) * y0 + f32x8::splat(2.) * y1 - half * y2;
+    let c3 = half * (y2 - ym) + f32x8::splat(1.5) * (y0 - y1);
+    ((c3 * f + c2) * f + c1) * f + y0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::simd::f32x8;
+
+    use super::{Interpolation, Lut, DB_TO_GAIN, TANH};
+
+    /// Largest absolute error of `lut` against `f` over `[min, max]`.
+    fn max_error(lut: &Lut, f: impl Fn(f32) -> f32, min: f32, max: f32) -> f32 {
+        (0..=10000)
+            .map(|i| min + (max - min) * i as f32 / 10000.)
+            .map(|x| (lut.get(x) - f(x)).abs())
+            .fold(0., f32::max)
+    }
+
+    #[test]
+    fn error_bounds() {
+        // With a step of h, nearest lookup is off by at most h/2 |f'|, linear interpolation by
+        // h²/8 |f''| and cubic interpolation by about h³/16 |f'''|. For tanh, |f'| <= 1,
+        // |f''| < 0.77 and |f'''| <= 2.
+        let h = 4. / 255.;
+        for (interpolation, bound) in [
+            (Interpolation::Nearest, h / 2.),
+            (Interpolation::Linear, h * h / 8. * 0.77),
+            (Interpolation::Cubic, h * h * h / 16. * 2.),
+        ] {
+            let lut = Lut::new(f32::tanh, -2.0..=2., 256, interpolation);
+            let error = max_error(&lut, f32::tanh, -2., 2.);
+            assert!(
+                error <= bound + 1e-6,
+                "{interpolation:?}: {error} > {bound}"
+            );
+        }
+
+        approx::assert_abs_diff_eq!(0., max_error(&TANH, f32::tanh, -10., 10.), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn db_to_gain() {
+        for i in -960..960 {
+            let db = i as f32 / 10. + 0.05;
+            let expected = 10f32.powf(db / 20.);
+            approx::assert_relative_eq!(expected, DB_TO_GAIN.get(db), max_relative = 1e-5);
+        }
+    }
+
+    #[test]
+    fn clamps_outside_of_range() {
+        let lut = Lut::new(|x| x * x, -1.0..=2., 64, Interpolation::Cubic);
+        approx::assert_abs_diff_eq!(1., lut.get(-5.), epsilon = 1e-6);
+        approx::assert_abs_diff_eq!(4., lut.get(5.), epsilon = 1e-6);
+
+        let odd = Lut::odd(f32::tanh, 3., 64, Interpolation::Linear);
+        approx::assert_abs_diff_eq!(3f32.tanh(), odd.get(10.), epsilon = 1e-6);
+        approx::assert_abs_diff_eq!(-3f32.tanh(), odd.get(-10.), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        for interpolation in [
+            Interpolation::Nearest,
+            Interpolation::Linear,
+            Interpolation::Cubic,
+        ] {
+            for lut in [
+                Lut::new(f32::sin, -3.0..=3., 100, interpolation),
+                Lut::odd(f32::tanh, 3., 100, interpolation),
+            ] {
+                for i in -20..20 {
+                    let x =
+                        f32x8::from_array(std::array::from_fn(|j| (8 * i + j as i32) as f32 / 37.));
+                    let expected = x.to_array().map(|x| lut.get(x));
+                    let actual = lut.get_simd(x).to_array();
+                    approx::assert_abs_diff_eq!(&expected[..], &actual[..], epsilon = 1e-6);
+                }
+            }
+        }
+    }
+}
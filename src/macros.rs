@@ -0,0 +1,169 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mod_matrix::{ModBuffers, ModDestination},
+    MAX_BLOCK_SIZE,
+};
+
+/// The number of macro knobs exposed to the host.
+pub const NUM_MACROS: usize = 8;
+
+#[derive(Params)]
+pub struct MacroParams {
+    #[id = "value"]
+    pub value: FloatParam,
+}
+
+impl fmt::Debug for MacroParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MacroParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for MacroParams {
+    fn default() -> Self {
+        Self {
+            value: FloatParam::new("Macro", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2))
+                .with_smoother(SmoothingStyle::Linear(20.)),
+        }
+    }
+}
+
+/// Response curve of a macro mapping, applied to the macro's position before interpolating
+/// between the mapping's `min` and `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MacroCurve {
+    Linear,
+    /// Slow start, fast end.
+    Exponential,
+    /// Fast start, slow end.
+    Logarithmic,
+    /// Slow at both ends.
+    SCurve,
+}
+
+impl MacroCurve {
+    #[inline]
+    pub fn apply(self, x: f32) -> f32 {
+        match self {
+            Self::Linear => x,
+            Self::Exponential => x * x,
+            Self::Logarithmic => 1. - (1. - x).powi(2),
+            Self::SCurve => x * x * (3. - 2. * x),
+        }
+    }
+}
+
+/// Mapping from one macro to one modulation destination. `min` and `max` are the modulation depths
+/// (as fractions of the destination's range) at either end of the macro's travel.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MacroMapping {
+    #[serde(rename = "macro")]
+    pub macro_idx: usize,
+    pub destination: ModDestination,
+    pub min: f32,
+    pub max: f32,
+    pub curve: MacroCurve,
+}
+
+impl MacroMapping {
+    #[inline]
+    pub fn depth(&self, x: f32) -> f32 {
+        self.min + (self.max - self.min) * self.curve.apply(x)
+    }
+}
+
+/// Render the macro mappings into `out` for the next `block_len` samples. `values` holds the
+/// smoothed position of each macro over the block.
+pub fn render(
+    mappings: &[MacroMapping],
+    values: &[[f32; MAX_BLOCK_SIZE]; NUM_MACROS],
+    block_len: usize,
+    out: &mut ModBuffers,
+) {
+    let mut depths = [0.; MAX_BLOCK_SIZE];
+    for mapping in mappings.iter().filter(|m| m.macro_idx < NUM_MACROS) {
+        for (depth, x) in depths[..block_len]
+            .iter_mut()
+            .zip(values[mapping.macro_idx].iter())
+        {
+            *depth = mapping.depth(*x);
+        }
+        out.add(mapping.destination, 1., &depths, block_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_span_the_full_range() {
+        for curve in [
+            MacroCurve::Linear,
+            MacroCurve::Exponential,
+            MacroCurve::Logarithmic,
+            MacroCurve::SCurve,
+        ] {
+            assert_eq!(0., curve.apply(0.));
+            assert_eq!(1., curve.apply(1.));
+            let mut prev = 0.;
+            for i in 1..=100 {
+                let y = curve.apply(i as f32 / 100.);
+                assert!(y >= prev, "{curve:?} is not monotonic");
+                prev = y;
+            }
+        }
+    }
+
+    #[test]
+    fn one_macro_drives_several_destinations() {
+        let mappings = [
+            MacroMapping {
+                macro_idx: 2,
+                destination: ModDestination::Cutoff,
+                min: 0.,
+                max: 0.5,
+                curve: MacroCurve::Linear,
+            },
+            MacroMapping {
+                macro_idx: 2,
+                destination: ModDestination::Tilt,
+                min: 0.,
+                max: -1.,
+                curve: MacroCurve::Exponential,
+            },
+        ];
+        let mut values = [[0.; MAX_BLOCK_SIZE]; NUM_MACROS];
+        values[2][0] = 0.5;
+        values[2][1] = 1.;
+
+        let mut out = ModBuffers::default();
+        render(&mappings, &values, 2, &mut out);
+        let (cutoff, tilt) = (out.get(ModDestination::Cutoff), out.get(ModDestination::Tilt));
+        approx::assert_abs_diff_eq!(0.25 * ModDestination::Cutoff.range(), cutoff[0]);
+        approx::assert_abs_diff_eq!(0.5 * ModDestination::Cutoff.range(), cutoff[1]);
+        approx::assert_abs_diff_eq!(-0.25 * ModDestination::Tilt.range(), tilt[0]);
+        approx::assert_abs_diff_eq!(-ModDestination::Tilt.range(), tilt[1]);
+    }
+
+    #[test]
+    fn mappings_round_trip_through_json() {
+        let mapping = MacroMapping {
+            macro_idx: 0,
+            destination: ModDestination::AmpEnvTime,
+            min: -0.25,
+            max: 0.25,
+            curve: MacroCurve::SCurve,
+        };
+        let json = serde_json::to_string(&vec![mapping]).unwrap();
+        let parsed: Vec<MacroMapping> = serde_json::from_str(&json).unwrap();
+        assert_eq!(vec![mapping], parsed);
+    }
+}
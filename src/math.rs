@@ -1,31 +1,185 @@
-use nalgebra::{ComplexField, SMatrix, SVector, Scalar};
-use num_traits::Zero;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+use nalgebra::{ComplexField, Const, DimMin, SMatrix, SVector, Scalar};
+use num_traits::One;
+
+/// MIDI note around which key tracking pivots (C4); notes above it are scaled up, notes below down.
+pub const KEY_TRACK_CENTER: u8 = 60;
+
+/// Ratio to apply to a pitch-dependent quantity for the given note and tracking amount, where an
+/// amount of 1 follows the keyboard exactly (doubling every octave above `KEY_TRACK_CENTER`).
+#[inline]
+pub fn key_track(note: u8, amount: f32) -> f32 {
+    f32::exp2(amount * (note as f32 - KEY_TRACK_CENTER as f32) / 12.)
+}
 
 pub trait ScalarField<T, const N: usize> {
     fn eval(&self, x: &SVector<T, N>) -> SVector<T, N>;
     fn jacobian(&self, x: &SVector<T, N>) -> SMatrix<T, N, N>;
 }
 
+/// Newton-Raphson step for `s` at `x`, such that `x - step` is the next estimate. The Jacobian is
+/// LU-decomposed rather than inverted; `None` is returned when it is singular.
 pub fn nr_step<T: ComplexField + Scalar, S, const N: usize>(
     s: &S,
     x: &SVector<T, N>,
 ) -> Option<SVector<T, N>>
 where
     S: ScalarField<T, N>,
+    Const<N>: DimMin<Const<N>, Output = Const<N>>,
 {
-    let j = s.jacobian(x);
-    let Some(ij) = j.clone_owned().try_inverse() else {
-        eprintln!("Jacobian matrix non-invertible:\n{j}");
-        return None;
+    s.jacobian(x).lu().solve(&s.eval(x))
+}
+
+/// Number of times a Newton step gets halved when it fails to reduce the residual.
+const MAX_BACKTRACKS: usize = 4;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct NrStatsSnapshot {
+    pub solves: u64,
+    pub iterations: u64,
+    /// Solves that ran out of iterations, hit a singular Jacobian, couldn't reduce the residual or
+    /// produced non-finite values.
+    pub failures: u64,
+}
+
+/// Counters of the Newton-Raphson solves, shared lock-free so that they can be inspected from
+/// elsewhere. Solves are first counted on the thread doing them, and only added here by
+/// [`publish_nr_stats`], so that the audio thread doesn't touch atomics on every sample.
+#[derive(Debug)]
+pub struct NrStats {
+    solves: AtomicU64,
+    iterations: AtomicU64,
+    failures: AtomicU64,
+}
+
+impl NrStats {
+    pub const fn new() -> Self {
+        Self {
+            solves: AtomicU64::new(0),
+            iterations: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+        }
+    }
+
+    fn add(&self, counts: NrStatsSnapshot) {
+        self.solves.fetch_add(counts.solves, Relaxed);
+        self.iterations.fetch_add(counts.iterations, Relaxed);
+        self.failures.fetch_add(counts.failures, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NrStatsSnapshot {
+        NrStatsSnapshot {
+            solves: self.solves.load(Relaxed),
+            iterations: self.iterations.load(Relaxed),
+            failures: self.failures.load(Relaxed),
+        }
+    }
+}
+
+/// Statistics of all the solves done through [`nr_solve`], up to the last [`publish_nr_stats`] on
+/// each thread.
+pub static NR_STATS: NrStats = NrStats::new();
+
+thread_local! {
+    /// Solves done on this thread since the last [`publish_nr_stats`].
+    static PENDING_NR_STATS: Cell<NrStatsSnapshot> = const {
+        Cell::new(NrStatsSnapshot {
+            solves: 0,
+            iterations: 0,
+            failures: 0,
+        })
     };
-    Some(ij * s.eval(x))
+}
+
+fn record_nr_solve(iterations: usize, converged: bool) {
+    PENDING_NR_STATS.with(|pending| {
+        let mut counts = pending.get();
+        counts.solves += 1;
+        counts.iterations += iterations as u64;
+        counts.failures += u64::from(!converged);
+        pending.set(counts);
+    });
+}
+
+/// Add the solves done on this thread to [`NR_STATS`]. This is meant to be called once per block.
+pub fn publish_nr_stats() {
+    let counts = PENDING_NR_STATS.with(Cell::take);
+    if counts.solves > 0 {
+        NR_STATS.add(counts);
+    }
+}
+
+/// Solve `s(x) = 0` with Newton-Raphson iterations from the initial guess `x`, until the squared
+/// length of the Newton step falls below `tol` times `1 + |x|²` or after `max_iter` iterations. The
+/// tolerance is relative for large `x`, whose rounding errors alone could otherwise exceed it.
+///
+/// Steps that don't reduce the residual are halved a few times (a backtracking line search), which
+/// keeps the iteration from overshooting on strongly saturating functions. A step that still
+/// increases the residual, a singular Jacobian or non-finite values end the solve. When the solve
+/// doesn't converge, `x` is restored to the initial guess, typically the previous state. Returns
+/// whether the solve converged, and counts it for [`NR_STATS`].
+pub fn nr_solve<T: ComplexField + Scalar, S, const N: usize>(
+    s: &S,
+    x: &mut SVector<T, N>,
+    max_iter: usize,
+    tol: T::RealField,
+) -> bool
+where
+    S: ScalarField<T, N>,
+    Const<N>: DimMin<Const<N>, Output = Const<N>>,
+{
+    let initial = x.clone();
+    let mut residual = s.eval(x).norm_squared();
+    let mut converged = false;
+    let mut iterations = 0;
+    while iterations < max_iter {
+        iterations += 1;
+        let Some(mut step) = nr_step(s, x) else {
+            break;
+        };
+        // Checked on the full step, so that backtracking doesn't pass for convergence
+        let scale = T::RealField::one() + x.norm_squared();
+        let small = step.norm_squared() < tol.clone() * scale;
+        let mut next = &*x - &step;
+        let mut next_residual = s.eval(&next).norm_squared();
+        for _ in 0..MAX_BACKTRACKS {
+            if small || next_residual <= residual {
+                break;
+            }
+            step /= T::from_subset(&2.);
+            next = &*x - &step;
+            next_residual = s.eval(&next).norm_squared();
+        }
+        if !next_residual.is_finite() {
+            break;
+        }
+        // Around the root, rounding can make a tiny step increase the residual slightly
+        if small {
+            *x = next;
+            converged = true;
+            break;
+        }
+        if next_residual > residual {
+            break;
+        }
+        *x = next;
+        residual = next_residual;
+    }
+
+    if !converged {
+        *x = initial;
+    }
+    record_nr_solve(iterations, converged);
+    converged
 }
 
 pub trait Differential<T, const N: usize> {
     fn dv(&self, t: T, yprev: &SVector<T, N>) -> SVector<T, N>;
 }
 
-impl<'a, T, D, const N: usize> Differential<T, N> for &'a D
+impl<T, D, const N: usize> Differential<T, N> for &D
 where
     D: Differential<T, N>,
 {
@@ -48,7 +202,7 @@ pub fn trapezoidal_step<const N: usize>(
 mod tests {
     use approx::assert_abs_diff_eq;
     use nalgebra::{SMatrix, SVector};
-    use crate::math::{nr_step, ScalarField};
+    use crate::math::{nr_solve, nr_step, publish_nr_stats, ScalarField, NR_STATS};
 
     #[test]
     fn newton_rhapson_single() {
@@ -98,4 +252,60 @@ mod tests {
         assert_abs_diff_eq!(std::f64::consts::E, x[0], epsilon=1e-3);
         assert_abs_diff_eq!(1., x[1], epsilon=1e-3);
     }
-}
\ No newline at end of file
+
+    struct Atan;
+
+    impl ScalarField<f32, 1> for Atan {
+        fn eval(&self, x: &SVector<f32, 1>) -> SVector<f32, 1> {
+            x.map(f32::atan)
+        }
+
+        fn jacobian(&self, x: &SVector<f32, 1>) -> SMatrix<f32, 1, 1> {
+            SMatrix::<_, 1, 1>::new((1. + x[0] * x[0]).recip())
+        }
+    }
+
+    #[test]
+    fn line_search_prevents_overshoot() {
+        // Plain Newton-Raphson diverges on atan when starting further than ~1.39 from the root
+        let mut x = SVector::<f32, 1>::new(3.);
+        x -= nr_step(&Atan, &x).unwrap();
+        assert!(x[0].abs() > 3.);
+
+        let mut x = SVector::<f32, 1>::new(3.);
+        assert!(nr_solve(&Atan, &mut x, 16, 1e-10));
+        assert_abs_diff_eq!(0., x[0], epsilon = 1e-4);
+
+        // Too far for the halved steps to reduce the residual, so the guess is kept
+        let mut x = SVector::<f32, 1>::new(1e4);
+        assert!(!nr_solve(&Atan, &mut x, 16, 1e-10));
+        assert_eq!(1e4, x[0]);
+    }
+
+    #[test]
+    fn failed_solves_restore_guess() {
+        struct NoRoot;
+
+        impl ScalarField<f32, 1> for NoRoot {
+            fn eval(&self, x: &SVector<f32, 1>) -> SVector<f32, 1> {
+                x.map(|x| x * x + 1.)
+            }
+
+            fn jacobian(&self, x: &SVector<f32, 1>) -> SMatrix<f32, 1, 1> {
+                SMatrix::<_, 1, 1>::new(2. * x[0])
+            }
+        }
+
+        let failures = NR_STATS.snapshot().failures;
+        let mut x = SVector::<f32, 1>::new(0.);
+        assert!(!nr_solve(&NoRoot, &mut x, 4, 1e-8));
+        assert_eq!(0., x[0]);
+        // The first step reduces the residual before hitting the singular Jacobian at 0, the
+        // initial guess is restored all the same
+        let mut x = SVector::<f32, 1>::new(1.);
+        assert!(!nr_solve(&NoRoot, &mut x, 4, 1e-8));
+        assert_eq!(1., x[0]);
+        publish_nr_stats();
+        assert!(NR_STATS.snapshot().failures >= failures + 2);
+    }
+}
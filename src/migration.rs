@@ -0,0 +1,144 @@
+use nih_plug::wrapper::state::{ParamValue, PluginState};
+
+use crate::filter::{FilterEnvMode, FilterType};
+use crate::oscillator::OscillatorType;
+
+/// ID prefixes of the parameters of filters A and B.
+const FILTER_PREFIXES: [&str; 2] = ["voice_", "voice_fb_"];
+/// Lowest value of the filter cutoff parameters, in Hz.
+const MIN_CUTOFF_PARAM: f32 = 20.;
+
+/// Whether `version` is older than `than`, comparing their dot-separated numbers in order.
+fn older(version: &str, than: &str) -> bool {
+    let numbers = |v: &str| -> Vec<u32> { v.split('.').map(|x| x.parse().unwrap_or(0)).collect() };
+    numbers(version) < numbers(than)
+}
+
+/// Index of the variant of the enum parameter `id`, or `default` if the state doesn't have it.
+fn enum_value(state: &PluginState, id: &str, default: i32) -> i32 {
+    match state.params.get(id) {
+        Some(ParamValue::I32(x)) => *x,
+        _ => default,
+    }
+}
+
+/// Update a state saved by an older version of the plugin so that it keeps sounding the same.
+pub fn migrate(state: &mut PluginState) {
+    if older(&state.version, "0.0.2") {
+        // Voices played a polyBLEP saw before the waveform parameter was added
+        state
+            .params
+            .entry("voice_osc".to_string())
+            .or_insert(ParamValue::I32(OscillatorType::Classic as i32));
+
+        // The ladder's cutoff used to sit an octave below the parameter. Halving the parameter,
+        // and the envelope amount when it is in Hz, puts it back where it was.
+        // Both are the defaults of the filter parameters
+        let (ladder, linear) = (FilterType::Ladder as i32, FilterEnvMode::Linear as i32);
+        for prefix in FILTER_PREFIXES {
+            let id = |id: &str| format!("{prefix}{id}");
+            if enum_value(state, &id("ftype"), ladder) != ladder {
+                continue;
+            }
+            let env_in_hz = enum_value(state, &id("fmodmode"), linear) == linear;
+            let mut halve = |id: String, min: f32| {
+                if let Some(ParamValue::F32(hz)) = state.params.get_mut(&id) {
+                    *hz = (*hz / 2.).max(min);
+                }
+            };
+            halve(id("fhz"), MIN_CUTOFF_PARAM);
+            if env_in_hz {
+                halve(id("fmod"), 0.);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nih_plug::wrapper::state::{ParamValue, PluginState};
+
+    use super::{migrate, older};
+    use crate::filter::{FilterEnvMode, FilterType};
+    use crate::oscillator::OscillatorType;
+
+    fn state(version: &str, params: &[(&str, ParamValue)]) -> PluginState {
+        PluginState {
+            version: version.to_string(),
+            params: params
+                .iter()
+                .map(|(id, value)| (id.to_string(), value.clone()))
+                .collect(),
+            fields: Default::default(),
+        }
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert!(older("0.0.1", "0.0.2"));
+        assert!(older("0.0.9", "0.1.0"));
+        assert!(older("", "0.0.2"));
+        assert!(!older("0.0.2", "0.0.2"));
+        assert!(!older("0.0.10", "0.0.2"));
+    }
+
+    #[test]
+    fn old_states_play_the_classic_saw() {
+        let classic = ParamValue::I32(OscillatorType::Classic as i32);
+        let mut old = state("0.0.1", &[("voice_fhz", ParamValue::F32(300.))]);
+        migrate(&mut old);
+        assert_eq!(Some(&classic), old.params.get("voice_osc"));
+
+        let square = ParamValue::I32(OscillatorType::Square as i32);
+        for version in ["0.0.1", "0.0.2"] {
+            let mut state = state(version, &[("voice_osc", square.clone())]);
+            migrate(&mut state);
+            assert_eq!(Some(&square), state.params.get("voice_osc"));
+        }
+
+        let mut new = state("0.0.2", &[("voice_fhz", ParamValue::F32(300.))]);
+        migrate(&mut new);
+        assert_eq!(1, new.params.len());
+    }
+
+    #[test]
+    fn old_ladder_cutoffs_are_halved() {
+        let mut old = state(
+            "0.0.1",
+            &[
+                ("voice_fhz", ParamValue::F32(1000.)),
+                ("voice_fmod", ParamValue::F32(3000.)),
+                ("voice_fb_ftype", ParamValue::I32(FilterType::Svf as i32)),
+                ("voice_fb_fhz", ParamValue::F32(1000.)),
+            ],
+        );
+        migrate(&mut old);
+        assert_eq!(Some(&ParamValue::F32(500.)), old.params.get("voice_fhz"));
+        assert_eq!(Some(&ParamValue::F32(1500.)), old.params.get("voice_fmod"));
+        assert_eq!(
+            Some(&ParamValue::F32(1000.)),
+            old.params.get("voice_fb_fhz")
+        );
+
+        // The envelope amount in octaves doesn't depend on the cutoff, and the cutoff stays in
+        // the parameter's range
+        let mut old = state(
+            "0.0.1",
+            &[
+                ("voice_fhz", ParamValue::F32(30.)),
+                (
+                    "voice_fmodmode",
+                    ParamValue::I32(FilterEnvMode::Exponential as i32),
+                ),
+                ("voice_fmod", ParamValue::F32(3000.)),
+            ],
+        );
+        migrate(&mut old);
+        assert_eq!(Some(&ParamValue::F32(20.)), old.params.get("voice_fhz"));
+        assert_eq!(Some(&ParamValue::F32(3000.)), old.params.get("voice_fmod"));
+
+        let mut new = state("0.0.2", &[("voice_fhz", ParamValue::F32(1000.))]);
+        migrate(&mut new);
+        assert_eq!(Some(&ParamValue::F32(1000.)), new.params.get("voice_fhz"));
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::lfo::NUM_LFOS;
+use crate::MAX_BLOCK_SIZE;
+
+/// The number of routing slots in the modulation matrix.
+pub const NUM_MOD_SLOTS: usize = 8;
+/// Counted from the last variant, which must stay last.
+const NUM_SOURCES: usize = ModSource::Random as usize + 1;
+/// Counted from the last variant, which must stay last.
+const NUM_DESTINATIONS: usize = ModDestination::SyncRatio as usize + 1;
+
+// Each LFO needs its own source and rate destination
+const _: () = assert!(NUM_LFOS == 2, "add a source and a rate destination for the new LFOs");
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModSource {
+    None,
+    #[name = "Amp Envelope"]
+    AmpEnv,
+    #[name = "Filter Envelope"]
+    FilterEnv,
+    #[name = "LFO 1"]
+    Lfo1,
+    #[name = "LFO 2"]
+    Lfo2,
+    Velocity,
+    Key,
+    #[name = "Mod Wheel"]
+    ModWheel,
+    Aftertouch,
+    Random,
+}
+
+impl ModSource {
+    pub const fn lfo(i: usize) -> Self {
+        match i {
+            0 => Self::Lfo1,
+            1 => Self::Lfo2,
+            _ => panic!("there are only two LFOs"),
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModDestination {
+    None,
+    Cutoff,
+    Resonance,
+    Drive,
+    #[name = "Filter Env Amount"]
+    FilterEnvAmount,
+    Pitch,
+    #[name = "Partial Tilt"]
+    Tilt,
+    Pan,
+    Amplitude,
+    #[name = "Amp Env Time"]
+    AmpEnvTime,
+    #[name = "Filter Env Time"]
+    FilterEnvTime,
+    #[name = "LFO 1 Rate"]
+    Lfo1Rate,
+    #[name = "LFO 2 Rate"]
+    Lfo2Rate,
+    #[name = "Morph Position"]
+    Morph,
+    #[name = "Wavetable Position"]
+    WavetablePosition,
+    #[name = "FM Index"]
+    FmIndex,
+    #[name = "Sync Ratio"]
+    SyncRatio,
+}
+
+impl ModDestination {
+    /// Modulation amount at full depth, in the destination's unit: octaves for cutoff, envelope
+    /// times, LFO rates and the sync ratio, semitones for pitch, dB for drive, dB/octave for tilt,
+    /// Hz for the filter envelope amount and plain values otherwise. When the filter envelope is in
+    /// octaves, its amount is scaled so that full depth spans its whole range instead.
+    pub const fn range(self) -> f32 {
+        match self {
+            Self::None => 0.,
+            Self::Cutoff => 8.,
+            Self::Resonance => 16.,
+            Self::Drive => 36.,
+            Self::FilterEnvAmount => 10e3,
+            Self::Pitch => 24.,
+            Self::Tilt => 12.,
+            Self::Pan => 1.,
+            Self::Amplitude => 1.,
+            Self::AmpEnvTime | Self::FilterEnvTime => 4.,
+            Self::Lfo1Rate | Self::Lfo2Rate => 4.,
+            Self::Morph | Self::WavetablePosition => 1.,
+            Self::FmIndex => 8.,
+            Self::SyncRatio => 4.,
+        }
+    }
+
+    pub const fn lfo_rate(i: usize) -> Self {
+        match i {
+            0 => Self::Lfo1Rate,
+            1 => Self::Lfo2Rate,
+            _ => panic!("there are only two LFOs"),
+        }
+    }
+}
+
+// The IDs are prefixed as the slots sit next to the LFOs in `VoiceParams`, whose destination and
+// depth would otherwise get the same IDs
+#[derive(Params)]
+pub struct ModSlotParams {
+    #[id = "slotsrc"]
+    pub source: EnumParam<ModSource>,
+    #[id = "slotdest"]
+    pub destination: EnumParam<ModDestination>,
+    #[id = "slotdepth"]
+    pub depth: FloatParam,
+    /// Secondary source scaling the depth of this slot.
+    #[id = "slotvia"]
+    pub via: EnumParam<ModSource>,
+    #[id = "slotviaamt"]
+    pub via_amount: FloatParam,
+}
+
+impl fmt::Debug for ModSlotParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModSlotParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for ModSlotParams {
+    fn default() -> Self {
+        Self {
+            source: EnumParam::new("Source", ModSource::None),
+            destination: EnumParam::new("Destination", ModDestination::None),
+            depth: depth_param("Depth"),
+            via: EnumParam::new("Depth Source", ModSource::None),
+            via_amount: depth_param("Depth Modulation"),
+        }
+    }
+}
+
+pub fn depth_param(name: impl ToString) -> FloatParam {
+    FloatParam::new(name.to_string(), 0., FloatRange::Linear { min: -1., max: 1. })
+        .with_string_to_value(formatters::s2v_f32_percentage())
+        .with_value_to_string(formatters::v2s_f32_percentage(2))
+}
+
+/// Per-sample values of every modulation source over one block. Envelopes, velocity, the mod
+/// wheel and aftertouch are unipolar, the LFOs, key and random sources are bipolar.
+#[derive(Debug, Clone)]
+pub struct ModSources([[f32; MAX_BLOCK_SIZE]; NUM_SOURCES]);
+
+impl Default for ModSources {
+    fn default() -> Self {
+        Self([[0.; MAX_BLOCK_SIZE]; NUM_SOURCES])
+    }
+}
+
+impl ModSources {
+    #[inline]
+    pub fn get(&self, source: ModSource) -> &[f32; MAX_BLOCK_SIZE] {
+        &self.0[source as usize]
+    }
+
+    /// Mutable access to a source's buffer. The `None` source must stay silent.
+    #[inline]
+    pub fn get_mut(&mut self, source: ModSource) -> &mut [f32; MAX_BLOCK_SIZE] {
+        debug_assert_ne!(source, ModSource::None);
+        &mut self.0[source as usize]
+    }
+}
+
+/// Per-sample modulation offsets of every destination over one block, in the units given by
+/// `ModDestination::range`.
+#[derive(Debug, Clone)]
+pub struct ModBuffers([[f32; MAX_BLOCK_SIZE]; NUM_DESTINATIONS]);
+
+impl Default for ModBuffers {
+    fn default() -> Self {
+        Self([[0.; MAX_BLOCK_SIZE]; NUM_DESTINATIONS])
+    }
+}
+
+impl ModBuffers {
+    #[inline]
+    pub fn get(&self, destination: ModDestination) -> &[f32; MAX_BLOCK_SIZE] {
+        &self.0[destination as usize]
+    }
+
+    /// Add `depth` (scaled to the destination's range) times `source` to the destination.
+    pub fn add(
+        &mut self,
+        destination: ModDestination,
+        depth: f32,
+        source: &[f32; MAX_BLOCK_SIZE],
+        block_len: usize,
+    ) {
+        if destination == ModDestination::None {
+            return;
+        }
+        let amount = depth * destination.range();
+        for (out, src) in self.0[destination as usize][..block_len]
+            .iter_mut()
+            .zip(source.iter())
+        {
+            *out += amount * src;
+        }
+    }
+
+    /// Evaluate the matrix slots over the block, accumulating into the destination buffers.
+    pub fn evaluate(&mut self, slots: &[ModSlotParams], sources: &ModSources, block_len: usize) {
+        for slot in slots {
+            let (source, destination) = (slot.source.value(), slot.destination.value());
+            if source == ModSource::None || destination == ModDestination::None {
+                continue;
+            }
+
+            let depth = slot.depth.value();
+            let via_amount = slot.via_amount.value();
+            let range = destination.range();
+            let out = &mut self.0[destination as usize][..block_len];
+            let source = sources.get(source);
+            match slot.via.value() {
+                ModSource::None => {
+                    for (out, src) in out.iter_mut().zip(source.iter()) {
+                        *out += range * depth * src;
+                    }
+                }
+                via => {
+                    let via = sources.get(via);
+                    for ((out, src), via) in out.iter_mut().zip(source.iter()).zip(via.iter()) {
+                        *out += range * (depth + via_amount * via) * src;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_match_enums() {
+        assert_eq!(NUM_SOURCES, ModSource::variants().len());
+        assert_eq!(NUM_DESTINATIONS, ModDestination::variants().len());
+    }
+
+    #[test]
+    fn depth_modulation() {
+        let slot = ModSlotParams {
+            source: EnumParam::new("Source", ModSource::Lfo1),
+            destination: EnumParam::new("Destination", ModDestination::Pitch),
+            depth: FloatParam::new("Depth", 0.5, FloatRange::Linear { min: -1., max: 1. }),
+            via: EnumParam::new("Depth Source", ModSource::ModWheel),
+            via_amount: FloatParam::new("Via", 0.5, FloatRange::Linear { min: -1., max: 1. }),
+        };
+
+        let mut sources = ModSources::default();
+        sources.get_mut(ModSource::Lfo1).fill(1.);
+        sources.get_mut(ModSource::ModWheel)[..2].copy_from_slice(&[0., 1.]);
+
+        let mut buffers = ModBuffers::default();
+        buffers.evaluate(&[slot], &sources, 2);
+        let pitch = buffers.get(ModDestination::Pitch);
+        approx::assert_abs_diff_eq!(12., pitch[0]);
+        approx::assert_abs_diff_eq!(24., pitch[1]);
+        assert_eq!(0., pitch[2]);
+        assert!(buffers.get(ModDestination::Cutoff).iter().all(|&x| x == 0.));
+    }
+}
@@ -0,0 +1,419 @@
+use std::array;
+use std::fmt;
+use std::fmt::Formatter;
+use std::simd::{f32x8, SimdFloat, SimdPartialEq, SimdPartialOrd};
+
+use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+use crate::{externs::SimdTrig, phasor::Phasor8};
+
+/// Maximum number of modes of a modal table.
+pub const MAX_MODES: usize = 64;
+const CHUNKS: usize = MAX_MODES / 8;
+const TAU: f32x8 = f32x8::from_array([std::f32::consts::TAU; 8]);
+
+/// One resonant mode of a modal table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mode {
+    /// Frequency relative to the note's.
+    pub ratio: f32,
+    pub amplitude: f32,
+    /// Decay time relative to the global decay time.
+    pub t60: f32,
+}
+
+const fn mode(ratio: f32, amplitude: f32, t60: f32) -> Mode {
+    Mode {
+        ratio,
+        amplitude,
+        t60,
+    }
+}
+
+/// Free-free bar, like a xylophone or marimba bar before tuning.
+const BAR: [Mode; 8] = [
+    mode(1., 1., 1.),
+    mode(2.756, 0.6, 0.7),
+    mode(5.404, 0.4, 0.5),
+    mode(8.933, 0.3, 0.38),
+    mode(13.345, 0.22, 0.3),
+    mode(18.638, 0.16, 0.24),
+    mode(24.812, 0.12, 0.2),
+    mode(31.870, 0.09, 0.16),
+];
+
+/// Simply supported square plate, whose modes are at `(m² + n²) / 2` times the fundamental.
+const PLATE: [Mode; 16] = [
+    mode(1., 1., 1.),
+    mode(2.5, 0.8, 0.9),
+    mode(4., 0.6, 0.8),
+    mode(5., 0.7, 0.75),
+    mode(6.5, 0.55, 0.7),
+    mode(8.5, 0.5, 0.62),
+    mode(9., 0.35, 0.6),
+    mode(10., 0.45, 0.56),
+    mode(12.5, 0.4, 0.5),
+    mode(13., 0.38, 0.48),
+    mode(14.5, 0.34, 0.45),
+    mode(16., 0.25, 0.42),
+    mode(17., 0.3, 0.4),
+    mode(18.5, 0.28, 0.38),
+    mode(20., 0.22, 0.35),
+    mode(20.5, 0.22, 0.34),
+];
+
+/// Church bell, with the prime on the note. The hum an octave below rings the longest.
+const BELL: [Mode; 11] = [
+    mode(0.5, 0.6, 1.6),
+    mode(1., 0.8, 1.),
+    mode(1.183, 0.7, 0.8),
+    mode(1.506, 0.3, 0.6),
+    mode(2., 1., 0.7),
+    mode(2.514, 0.3, 0.45),
+    mode(2.662, 0.35, 0.4),
+    mode(3.011, 0.25, 0.35),
+    mode(4.166, 0.2, 0.25),
+    mode(5.433, 0.12, 0.2),
+    mode(6.796, 0.08, 0.15),
+];
+
+/// Ideal circular membrane, with the modes at the zeros of the Bessel functions.
+const MEMBRANE: [Mode; 12] = [
+    mode(1., 1., 1.),
+    mode(1.594, 0.8, 0.8),
+    mode(2.136, 0.6, 0.7),
+    mode(2.296, 0.5, 0.65),
+    mode(2.653, 0.45, 0.6),
+    mode(2.918, 0.4, 0.55),
+    mode(3.156, 0.35, 0.5),
+    mode(3.501, 0.3, 0.45),
+    mode(3.6, 0.28, 0.42),
+    mode(3.652, 0.25, 0.4),
+    mode(4.06, 0.2, 0.38),
+    mode(4.154, 0.18, 0.35),
+];
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModalPreset {
+    Bar,
+    Plate,
+    Bell,
+    Membrane,
+}
+
+impl ModalPreset {
+    pub fn modes(self) -> &'static [Mode] {
+        match self {
+            Self::Bar => &BAR,
+            Self::Plate => &PLATE,
+            Self::Bell => &BELL,
+            Self::Membrane => &MEMBRANE,
+        }
+    }
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Excitation {
+    /// An impulse scaled by the note's velocity.
+    Strike,
+    /// A burst of white noise scaled by the note's velocity, for softer, less defined attacks.
+    #[name = "Noise Burst"]
+    Noise,
+}
+
+#[derive(Params)]
+pub struct ModalParams {
+    #[id = "mdpreset"]
+    pub preset: EnumParam<ModalPreset>,
+    /// Decay time of the first mode, in seconds.
+    #[id = "mddecay"]
+    pub decay: FloatParam,
+    /// How much faster the higher modes decay, as the exponent of their frequency ratio.
+    #[id = "mddamp"]
+    pub damping: FloatParam,
+    #[id = "mdexc"]
+    pub excitation: EnumParam<Excitation>,
+    /// Length of the noise burst, in milliseconds.
+    #[id = "mdburst"]
+    pub burst: FloatParam,
+}
+
+impl fmt::Debug for ModalParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModalParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for ModalParams {
+    fn default() -> Self {
+        Self {
+            preset: EnumParam::new("Modal Preset", ModalPreset::Bar),
+            decay: FloatParam::new(
+                "Modal Decay",
+                2.,
+                FloatRange::Skewed {
+                    min: 0.01,
+                    max: 20.,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_unit(" s")
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            damping: FloatParam::new(
+                "Modal Damping",
+                0.5,
+                FloatRange::Linear { min: 0., max: 2. },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(2)),
+            excitation: EnumParam::new("Excitation", Excitation::Strike),
+            burst: FloatParam::new(
+                "Burst Length",
+                10.,
+                FloatRange::Skewed {
+                    min: 1.,
+                    max: 200.,
+                    factor: FloatRange::skew_factor(-1.),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+        }
+    }
+}
+
+/// Noise burst exciting the modes after note-on.
+#[derive(Debug, Clone)]
+struct Burst {
+    prng: Pcg32,
+    /// Samples left in the burst.
+    remaining: u32,
+    len: u32,
+    gain: f32,
+}
+
+impl Burst {
+    #[inline]
+    fn next(&mut self) -> f32 {
+        if self.remaining == 0 {
+            return 0.;
+        }
+        // Linearly decaying uniform noise, with unit variance at the start
+        let env = self.remaining as f32 / self.len as f32;
+        self.remaining -= 1;
+        self.gain * env * 3f32.sqrt() * self.prng.gen_range(-1f32..1.)
+    }
+}
+
+/// Bank of exponentially decaying sine resonators, each running on a lane of a [`Phasor8`].
+///
+/// Each mode is a complex one-pole resonator, written as an amplitude `a` modulating its phasor:
+/// the output is `Im(a e^{iθ})` and the input is demodulated by `e^{-iθ}` before being added to
+/// `a`, which keeps the phasors free to follow pitch modulation.
+#[derive(Debug, Clone)]
+pub struct Modal {
+    samplerate: f32,
+    phasors: [Phasor8; CHUNKS],
+    gains: [f32x8; CHUNKS],
+    /// Decay time of each mode relative to the global one, before damping.
+    t60: [f32x8; CHUNKS],
+    /// Per-sample decay factor of each mode.
+    decay: [f32x8; CHUNKS],
+    re: [f32x8; CHUNKS],
+    im: [f32x8; CHUNKS],
+    /// Decay time and damping `decay` was computed from.
+    settings: (f32, f32),
+    burst: Option<Burst>,
+}
+
+impl Modal {
+    /// Start the modes of a note at `hz` played at `velocity`. The noise burst is seeded from
+    /// `prng`.
+    pub fn new(
+        params: &ModalParams,
+        samplerate: f32,
+        hz: f32,
+        velocity: f32,
+        prng: &mut Pcg32,
+    ) -> Self {
+        let modes = params.preset.value().modes();
+        let lanes = |f: fn(&Mode) -> f32| -> [f32x8; CHUNKS] {
+            array::from_fn(|i| {
+                f32x8::from_array(array::from_fn(|j| modes.get(8 * i + j).map_or(0., f)))
+            })
+        };
+        let ratios = lanes(|mode| mode.ratio);
+        let mut this = Self {
+            samplerate,
+            phasors: ratios
+                .map(|ratio| Phasor8::new(f32x8::splat(samplerate), ratio * f32x8::splat(hz))),
+            gains: lanes(|mode| mode.amplitude),
+            t60: lanes(|mode| mode.t60),
+            decay: [f32x8::splat(0.); CHUNKS],
+            re: [f32x8::splat(0.); CHUNKS],
+            im: [f32x8::splat(0.); CHUNKS],
+            settings: (f32::NAN, f32::NAN),
+            burst: None,
+        };
+        this.set_decay(params.decay.value(), params.damping.value());
+        match params.excitation.value() {
+            Excitation::Strike => this.re = this.gains.map(|gain| gain * f32x8::splat(velocity)),
+            Excitation::Noise => {
+                let len = (params.burst.value() * 1e-3 * samplerate).max(1.) as u32;
+                this.burst = Some(Burst {
+                    prng: Pcg32::new(prng.gen(), prng.gen()),
+                    remaining: len,
+                    len,
+                    // The energy of the burst is about `len / 3`, this makes the modes ring at
+                    // about the same level as when struck
+                    gain: velocity * (3. / len as f32).sqrt(),
+                });
+            }
+        }
+        this
+    }
+
+    /// Set the decay time of the first mode in seconds, and the damping of the higher modes. The
+    /// decay factors are only recomputed when either changes, so this is meant to be called at
+    /// block rate.
+    pub fn set_decay(&mut self, decay: f32, damping: f32) {
+        if (decay, damping) == self.settings {
+            return;
+        }
+        self.settings = (decay, damping);
+        let f0 = self.phasors[0].hz[0];
+        for ((out, t60), phasor) in self.decay.iter_mut().zip(&self.t60).zip(&self.phasors) {
+            *out = f32x8::from_array(array::from_fn(|j| {
+                if phasor.hz[j] <= 0. {
+                    return 0.;
+                }
+                let t60 = decay * t60[j] * (phasor.hz[j] / f0).powf(-damping);
+                // -60 dB after `t60` seconds
+                (-1000f32.ln() / (t60 * self.samplerate)).exp()
+            }));
+        }
+    }
+
+    /// Render the next sample of the modes, with all frequencies multiplied by `pitch`.
+    #[inline(always)]
+    pub fn sample(&mut self, pitch: f32) -> f32 {
+        let [low, high] = self.sample_split(pitch, f32::INFINITY);
+        low + high
+    }
+
+    /// Render the next sample like [`Self::sample`], but as two separate sums: one of the modes up
+    /// to `split` times the first mode's frequency, and one of the modes above it.
+    #[inline(always)]
+    pub fn sample_split(&mut self, pitch: f32, split: f32) -> [f32; 2] {
+        let input = f32x8::splat(self.burst.as_mut().map_or(0., Burst::next));
+        let nyquist = f32x8::splat(self.samplerate / 2.0);
+        let threshold = f32x8::splat((split + 0.5) * self.phasors[0].hz[0]);
+        let pitch = f32x8::splat(pitch);
+        let zero = f32x8::splat(0.);
+        let (mut low, mut high) = (zero, zero);
+        for i in 0..CHUNKS {
+            let phasor = &mut self.phasors[i];
+            let mask = self.gains[i].simd_ne(zero) & (phasor.hz * pitch).simd_lt(nyquist);
+            if !mask.any() {
+                continue;
+            }
+            let theta = TAU * phasor.phase;
+            let (sin, cos) = (theta.sin(), theta.cos());
+            let (re, im) = (&mut self.re[i], &mut self.im[i]);
+            let x = input * self.gains[i];
+            *re = self.decay[i] * *re + x * cos;
+            *im = self.decay[i] * *im - x * sin;
+            let y = mask.select(*im * cos + *re * sin, zero);
+            let is_low = phasor.hz.simd_lt(threshold);
+            low += is_low.select(y, zero);
+            high += is_low.select(zero, y);
+            phasor.advance(pitch);
+        }
+        [low.reduce_sum(), high.reduce_sum()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nih_plug::prelude::*;
+    use rand_pcg::Pcg32;
+
+    use super::{Excitation, Modal, ModalParams, ModalPreset};
+
+    fn params(preset: ModalPreset, excitation: Excitation) -> ModalParams {
+        ModalParams {
+            preset: EnumParam::new("Modal Preset", preset),
+            excitation: EnumParam::new("Excitation", excitation),
+            ..ModalParams::default()
+        }
+    }
+
+    fn magnitude(modal: &Modal, i: usize) -> f32 {
+        modal.re[i / 8][i % 8].hypot(modal.im[i / 8][i % 8])
+    }
+
+    #[test]
+    fn modes_decay_by_60_db_at_t60() {
+        let mut prng = Pcg32::new(420, 1337);
+        let mut modal = Modal::new(
+            &params(ModalPreset::Bar, Excitation::Strike),
+            48e3,
+            220.,
+            1.,
+            &mut prng,
+        );
+        modal.set_decay(0.1, 1.);
+        approx::assert_abs_diff_eq!(1., magnitude(&modal, 0));
+        approx::assert_abs_diff_eq!(0.6, magnitude(&modal, 1));
+        for _ in 0..4800 {
+            modal.sample(1.);
+        }
+        approx::assert_relative_eq!(1e-3, magnitude(&modal, 0), max_relative = 1e-2);
+        // Scaled by its relative decay time, and damped by its frequency ratio
+        let t60 = 0.1 * 0.7 / 2.756;
+        let expected = 0.6 * 1e-3f32.powf(0.1 / t60);
+        approx::assert_relative_eq!(expected, magnitude(&modal, 1), max_relative = 1e-2);
+    }
+
+    #[test]
+    fn strike_rings_the_modes() {
+        let mut prng = Pcg32::new(420, 1337);
+        let mut modal = Modal::new(
+            &params(ModalPreset::Membrane, Excitation::Strike),
+            48e3,
+            100.,
+            0.5,
+            &mut prng,
+        );
+        modal.set_decay(1e3, 0.);
+        // The modes all start at a zero crossing, and ring at half their amplitude
+        let y: Vec<f32> = (0..480).map(|_| modal.sample(1.)).collect();
+        approx::assert_abs_diff_eq!(0., y[0]);
+        let expected = |t: f32| {
+            super::MEMBRANE
+                .iter()
+                .map(|mode| {
+                    0.5 * mode.amplitude * (std::f32::consts::TAU * mode.ratio * 100. * t).sin()
+                })
+                .sum::<f32>()
+        };
+        for (i, y) in y.into_iter().enumerate() {
+            approx::assert_abs_diff_eq!(expected(i as f32 / 48e3), y, epsilon = 2e-3);
+        }
+    }
+
+    #[test]
+    fn noise_burst_is_seeded() {
+        let render = || {
+            let mut prng = Pcg32::new(420, 1337);
+            let params = params(ModalPreset::Bell, Excitation::Noise);
+            let mut modal = Modal::new(&params, 48e3, 220., 1., &mut prng);
+            (0..4800).map(|_| modal.sample(1.)).collect::<Vec<_>>()
+        };
+        let y = render();
+        assert_eq!(y, render());
+        let rms = (y[2400..].iter().map(|y| y * y).sum::<f32>() / 2400.).sqrt();
+        assert!(rms > 0.1, "RMS of {rms} after the burst");
+    }
+}
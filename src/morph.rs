@@ -0,0 +1,271 @@
+use std::array;
+use std::fmt;
+use std::fmt::Formatter;
+use std::simd::f32x8;
+
+use nih_plug::prelude::*;
+
+use crate::oscillator::{Oscillator, OscillatorType};
+use crate::MORPH_POLY_MOD_ID;
+
+/// Maximum number of partial sets to morph between.
+pub const MAX_MORPH_SETS: usize = 4;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MorphSets {
+    Off,
+    #[name = "2 Sets"]
+    Two,
+    #[name = "4 Sets"]
+    Four,
+}
+
+impl MorphSets {
+    pub const fn count(self) -> usize {
+        match self {
+            Self::Off => 1,
+            Self::Two => 2,
+            Self::Four => 4,
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct MorphParams {
+    #[id = "msets"]
+    pub sets: EnumParam<MorphSets>,
+    /// Waveforms of the sets after the first one, which is the main waveform.
+    #[id = "oscb"]
+    pub osc_b: EnumParam<OscillatorType>,
+    #[id = "oscc"]
+    pub osc_c: EnumParam<OscillatorType>,
+    #[id = "oscd"]
+    pub osc_d: EnumParam<OscillatorType>,
+    #[id = "morph"]
+    pub position: FloatParam,
+    /// Whether to interpolate the frequencies of the partials as well as their gains.
+    #[id = "mfreq"]
+    pub frequencies: BoolParam,
+}
+
+impl fmt::Debug for MorphParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MorphParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for MorphParams {
+    fn default() -> Self {
+        Self {
+            sets: EnumParam::new("Morph Sets", MorphSets::Off),
+            osc_b: EnumParam::new("Waveform B", OscillatorType::Square),
+            osc_c: EnumParam::new("Waveform C", OscillatorType::Triangle),
+            osc_d: EnumParam::new("Waveform D", OscillatorType::Sine),
+            position: FloatParam::new("Morph", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2))
+                .with_smoother(SmoothingStyle::Linear(20.))
+                .with_poly_modulation_id(MORPH_POLY_MOD_ID),
+            frequencies: BoolParam::new("Morph Frequencies", false),
+        }
+    }
+}
+
+/// Gains and frequencies of a bank of partials, laid out like in [`Oscillator`].
+#[derive(Debug, Clone, Copy)]
+pub struct PartialSet {
+    gains: [f32x8; 128],
+    hz: [f32x8; 128],
+}
+
+impl Default for PartialSet {
+    fn default() -> Self {
+        Self {
+            gains: [f32x8::splat(0.); 128],
+            hz: [f32x8::splat(0.); 128],
+        }
+    }
+}
+
+/// Storage for the partial sets of one voice. Each set takes 8 kB, so the plugin allocates these
+/// once per voice slot rather than keeping them in the voices or allocating them on note-on.
+pub type PartialSets = [PartialSet; MAX_MORPH_SETS];
+
+impl PartialSet {
+    /// The partials of `osc`, in the order they are stored in.
+    pub fn from_oscillator(osc: &Oscillator) -> Self {
+        Self {
+            gains: osc.gains,
+            hz: array::from_fn(|i| osc.phasors[i].hz),
+        }
+    }
+
+    /// The partials of `osc`, each moved to the lane of the harmonic of `f0` nearest to it, so that
+    /// the sets of oscillators with different layouts line up. Partials falling on the same
+    /// harmonic are summed.
+    pub fn harmonic(osc: &Oscillator, f0: f32) -> Self {
+        let mut gains = [0f32; 1024];
+        for (gain, phasor) in osc.gains.iter().zip(osc.phasors.iter()) {
+            for (gain, hz) in gain.to_array().into_iter().zip(phasor.hz.to_array()) {
+                let n = (hz / f0).round() as usize;
+                if gain != 0. && (1..=gains.len()).contains(&n) {
+                    gains[n - 1] += gain;
+                }
+            }
+        }
+        Self {
+            gains: array::from_fn(|i| f32x8::from_slice(&gains[8 * i..8 * i + 8])),
+            hz: array::from_fn(|i| {
+                f32x8::from_array(array::from_fn(|j| (8 * i + j + 1) as f32 * f0))
+            }),
+        }
+    }
+}
+
+/// Interpolates between up to [`MAX_MORPH_SETS`] partial sets, writing the result into the
+/// oscillator. The sets are built once on note-on into the voice slot's [`PartialSets`], so moving
+/// the morph position only costs an interpolation per block.
+#[derive(Debug, Clone, Copy)]
+pub struct Morph {
+    /// Number of sets in use, at least two. The first one is the main waveform.
+    count: usize,
+    /// Position the oscillator was last updated at.
+    position: f32,
+}
+
+impl Morph {
+    /// Build the partial sets for a note at `hz` into `sets`, starting from the main oscillator
+    /// `osc` and building the others with `build`. Returns `None` when morphing is off.
+    pub fn new(
+        params: &MorphParams,
+        osc: &Oscillator,
+        hz: f32,
+        build: impl Fn(OscillatorType, f32) -> Oscillator,
+        sets: &mut PartialSets,
+    ) -> Option<Self> {
+        let count = params.sets.value().count();
+        if count < 2 {
+            return None;
+        }
+        let frequencies = params.frequencies.value();
+        let types = [
+            None,
+            Some(params.osc_b.value()),
+            Some(params.osc_c.value()),
+            Some(params.osc_d.value()),
+        ];
+        for (set, ty) in sets.iter_mut().zip(&types[..count]) {
+            let other = ty.map(|ty| build(ty, hz));
+            let osc = other.as_ref().unwrap_or(osc);
+            *set = if frequencies {
+                PartialSet::from_oscillator(osc)
+            } else {
+                PartialSet::harmonic(osc, hz)
+            };
+        }
+        Some(Self {
+            count,
+            position: f32::NAN,
+        })
+    }
+
+    /// Set the partials of `osc` to the interpolation of `sets`, as built by [`Self::new`], at
+    /// `position`, between 0 and 1. Does nothing if the position hasn't changed since the last
+    /// call.
+    pub fn apply(&mut self, sets: &PartialSets, osc: &mut Oscillator, position: f32) {
+        let position = position.clamp(0., 1.);
+        if position == self.position {
+            return;
+        }
+        self.position = position;
+
+        let x = position * (self.count - 1) as f32;
+        let i = (x as usize).min(self.count - 2);
+        let t = f32x8::splat(x - i as f32);
+        let (a, b) = (&sets[i], &sets[i + 1]);
+        osc.set_partials(|k| {
+            let gain = a.gains[k] + t * (b.gains[k] - a.gains[k]);
+            let hz = a.hz[k] + t * (b.hz[k] - a.hz[k]);
+            (gain, hz)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::simd::f32x8;
+
+    use nih_plug::prelude::*;
+
+    use super::{Morph, MorphParams, MorphSets, PartialSet, PartialSets};
+    use crate::oscillator::Oscillator;
+
+    const FS: f32 = 48e3;
+    const F0: f32 = 100.;
+
+    #[test]
+    fn harmonic_sets_line_up() {
+        let square = PartialSet::harmonic(&Oscillator::square(FS, F0), F0);
+        let gains: Vec<f32> = square.gains.iter().flat_map(|g| g.to_array()).collect();
+        approx::assert_abs_diff_eq!(4. / std::f32::consts::PI, gains[0]);
+        approx::assert_abs_diff_eq!(0., gains[1]);
+        approx::assert_abs_diff_eq!(4. / (3. * std::f32::consts::PI), gains[2]);
+        approx::assert_abs_diff_eq!(300., square.hz[0][2]);
+    }
+
+    #[test]
+    fn morph_interpolates_between_sets() {
+        let saw = Oscillator::saw(FS, F0);
+        let square = Oscillator::square(FS, F0);
+        let mut sets: PartialSets = Default::default();
+        sets[0] = PartialSet::harmonic(&saw, F0);
+        sets[1] = PartialSet::harmonic(&square, F0);
+        let morph = Morph {
+            count: 2,
+            position: f32::NAN,
+        };
+        let [a, b] = [&sets[0], &sets[1]];
+
+        for (position, t) in [(0., 0.), (0.25, 0.25), (1., 1.)] {
+            let mut osc = saw;
+            let mut morph = morph;
+            morph.apply(&sets, &mut osc, position);
+            for k in 0..128 {
+                let expected = a.gains[k] + f32x8::splat(t) * (b.gains[k] - a.gains[k]);
+                for (expected, actual) in
+                    expected.to_array().into_iter().zip(osc.gains[k].to_array())
+                {
+                    approx::assert_abs_diff_eq!(expected, actual, epsilon = 1e-6);
+                }
+                assert_eq!(a.hz[k], osc.phasors[k].hz);
+            }
+        }
+    }
+
+    #[test]
+    fn off_by_default() {
+        let osc = Oscillator::saw(FS, F0);
+        let build = |ty, hz| Oscillator::from_type(ty, FS, hz, &Default::default());
+        let mut sets = Default::default();
+        assert!(Morph::new(&MorphParams::default(), &osc, F0, build, &mut sets).is_none());
+    }
+
+    #[test]
+    fn only_used_sets_are_built() {
+        let osc = Oscillator::saw(FS, F0);
+        let built = Cell::new(0);
+        let build = |ty, hz| {
+            built.set(built.get() + 1);
+            Oscillator::from_type(ty, FS, hz, &Default::default())
+        };
+        let params = MorphParams {
+            sets: EnumParam::new("Morph Sets", MorphSets::Two),
+            ..MorphParams::default()
+        };
+        let mut sets = Default::default();
+        let morph = Morph::new(&params, &osc, F0, build, &mut sets).unwrap();
+        assert_eq!(2, morph.count);
+        assert_eq!(1, built.get());
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseColor {
+    White,
+    /// -3 dB per octave.
+    Pink,
+    /// -6 dB per octave.
+    Brown,
+}
+
+#[derive(Params)]
+pub struct NoiseParams {
+    #[id = "noisecol"]
+    pub color: EnumParam<NoiseColor>,
+    #[id = "noiselvl"]
+    pub level: FloatParam,
+}
+
+impl fmt::Debug for NoiseParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for NoiseParams {
+    fn default() -> Self {
+        Self {
+            color: EnumParam::new("Noise Color", NoiseColor::White),
+            level: FloatParam::new("Noise Level", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2))
+                .with_smoother(SmoothingStyle::Linear(10.)),
+        }
+    }
+}
+
+/// White, pink or brown noise. All colors are filtered from the same white noise, so switching
+/// between them doesn't click.
+#[derive(Debug, Clone)]
+pub struct Noise {
+    prng: Pcg32,
+    /// States of Paul Kellet's pink noise filter.
+    pink: [f32; 7],
+    /// State of the leaky integrator for brown noise.
+    brown: f32,
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            prng: Pcg32::seed_from_u64(seed),
+            pink: [0.; 7],
+            brown: 0.,
+        }
+    }
+
+    /// Render the next sample. The pink noise filter is tuned for 44.1 kHz, but stays close enough
+    /// to -3 dB per octave at the usual sample rates.
+    #[inline]
+    pub fn next(&mut self, color: NoiseColor) -> f32 {
+        let white = self.prng.gen_range(-1f32..1.);
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[..6].iter().sum::<f32>() + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        self.brown = (self.brown + 0.02 * white) / 1.02;
+        // Rough gains to bring the colors to about the same level
+        match color {
+            NoiseColor::White => white,
+            NoiseColor::Pink => 0.11 * pink,
+            NoiseColor::Brown => 3.5 * self.brown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Noise, NoiseColor};
+
+    fn render(color: NoiseColor) -> Vec<f32> {
+        let mut noise = Noise::new(1337);
+        (0..48000).map(|_| noise.next(color)).collect()
+    }
+
+    /// Power of the signal and of its first difference, which weighs the high frequencies more.
+    fn power(x: &[f32]) -> (f32, f32) {
+        let total = x.iter().map(|x| x * x).sum::<f32>() / x.len() as f32;
+        let diff = x.windows(2).map(|w| (w[1] - w[0]).powi(2)).sum::<f32>() / x.len() as f32;
+        (total, diff)
+    }
+
+    #[test]
+    fn colors_get_darker() {
+        let [white, pink, brown] =
+            [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown].map(|c| power(&render(c)));
+        // White noise has uncorrelated samples
+        approx::assert_relative_eq!(1. / 3., white.0, max_relative = 0.05);
+        approx::assert_relative_eq!(2., white.1 / white.0, max_relative = 0.05);
+        assert!(pink.1 / pink.0 < white.1 / white.0);
+        assert!(brown.1 / brown.0 < pink.1 / pink.0);
+        for power in [pink.0, brown.0] {
+            assert!((0.02..0.5).contains(&power), "Power of {power}");
+        }
+    }
+
+    #[test]
+    fn seeded() {
+        assert_eq!(render(NoiseColor::Pink), render(NoiseColor::Pink));
+        assert_ne!(render(NoiseColor::Pink), {
+            let mut noise = Noise::new(42);
+            (0..48000)
+                .map(|_| noise.next(NoiseColor::Pink))
+                .collect::<Vec<_>>()
+        });
+    }
+}
@@ -0,0 +1,77 @@
+use nih_plug::prelude::Enum;
+
+use crate::lut::TANH;
+
+/// Knee of the diode clipper, which saturates towards `±DIODE_PARAM`.
+pub const DIODE_PARAM: f32 = 0.2577819;
+/// Bias of the transistor model, shifting its operating point to get even harmonics.
+const TRANSISTOR_BIAS: f32 = 0.3;
+
+/// Saturation curve applied within the filter stages. Every model has unity gain around zero so
+/// that switching between them only changes the behavior at high levels.
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Nonlinearity {
+    Tanh,
+    /// Soft clipper `D x / (D + |x|)`, which saturates earlier and more gently than `tanh`.
+    Diode,
+    /// Biased `tanh`, clipping harder on one side than the other.
+    #[name = "Transistor"]
+    Transistor,
+    /// No saturation at all, leaving a linear filter.
+    Linear,
+}
+
+impl Nonlinearity {
+    #[inline(always)]
+    pub fn f(self, x: f32) -> f32 {
+        match self {
+            Self::Tanh => TANH.get(x),
+            Self::Diode => DIODE_PARAM * x / (DIODE_PARAM + x.abs()),
+            Self::Transistor => {
+                let b = TRANSISTOR_BIAS.tanh();
+                (TANH.get(x + TRANSISTOR_BIAS) - b) / (1. - b * b)
+            }
+            Self::Linear => x,
+        }
+    }
+
+    /// Derivative of [`Self::f`], as needed to fill in the Jacobian of the filter equations.
+    #[inline(always)]
+    pub fn df(self, x: f32) -> f32 {
+        match self {
+            Self::Tanh => 1. - TANH.get(x).powi(2),
+            Self::Diode => (DIODE_PARAM / (DIODE_PARAM + x.abs())).powi(2),
+            Self::Transistor => {
+                let b = TRANSISTOR_BIAS.tanh();
+                (1. - TANH.get(x + TRANSISTOR_BIAS).powi(2)) / (1. - b * b)
+            }
+            Self::Linear => 1.,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Nonlinearity;
+
+    const MODELS: [Nonlinearity; 4] = [
+        Nonlinearity::Tanh,
+        Nonlinearity::Diode,
+        Nonlinearity::Transistor,
+        Nonlinearity::Linear,
+    ];
+
+    #[test]
+    fn derivatives_match_finite_differences() {
+        const H: f32 = 1e-3;
+        for model in MODELS {
+            for i in -40..=40 {
+                let x = i as f32 / 8. + 0.01;
+                let expected = (model.f(x + H) - model.f(x - H)) / (2. * H);
+                approx::assert_abs_diff_eq!(expected, model.df(x), epsilon = 1e-2);
+            }
+            approx::assert_abs_diff_eq!(0., model.f(0.), epsilon = 1e-6);
+            approx::assert_abs_diff_eq!(1., model.df(0.), epsilon = 1e-6);
+        }
+    }
+}
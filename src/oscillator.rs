@@ -1,50 +1,161 @@
 use std::{
     array,
-    simd::{f32x8, mask32x8, SimdFloat},
+    f32::consts::PI,
+    simd::{f32x8, mask32x8, SimdFloat, SimdPartialEq, SimdPartialOrd},
 };
-use std::simd::u8x8;
 
-use crate::phasor::Phasor8;
+use nih_plug::prelude::*;
+
+use crate::{
+    externs::SimdTrig,
+    fm::{Fm, FmSource},
+    formant::FormantFilter,
+    harmonics::{HarmonicTable, MAX_HARMONICS},
+    mod_matrix::ModDestination,
+    phasor::Phasor8,
+    spectral::SpectralFilter,
+    sync::SyncShape,
+};
 
 const TAU: f32x8 = f32x8::from_array([std::f32::consts::TAU; 8]);
 
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OscillatorType {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// The harmonic table stored with the plugin state.
+    #[name = "Harmonic Table"]
+    Table,
+    /// A frame of the imported wavetable, see [`crate::wavetable::Wavetable`].
+    Wavetable,
+    /// Decaying resonators excited at note-on, see [`crate::modal::Modal`]. The partial bank is
+    /// left silent.
+    Modal,
+    /// The polyBLEP saw voices played before the partial bank, kept so that older patches sound
+    /// the same. The spectral controls don't apply to it.
+    #[name = "Classic Saw"]
+    Classic,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Oscillator {
-    pub phase_offset: f32,
     pub(crate) samplerate: f32,
     pub gains: [f32x8; 128],
     pub phasors: [Phasor8; 128],
+    /// Phase of each partial relative to its phasor, in cycles.
+    phase_offsets: [f32x8; 128],
+    /// Per-partial gain multipliers applied on top of `gains`, recomputed at block rate from the
+    /// spectral modulations.
+    shaping: [f32x8; 128],
+    /// Spectral tilt currently baked into `shaping`, in dB/octave.
+    tilt: f32,
+    /// Spectral filter currently baked into `shaping`.
+    filter: Option<SpectralFilter>,
+    /// Vowel envelope currently baked into `shaping`.
+    formant: Option<FormantFilter>,
+    /// Frequency modulation currently baked into `fm_scale`.
+    fm: Option<Fm>,
+    /// Per-partial multipliers of the FM index, zero for unmodulated partials.
+    fm_scale: [f32x8; 128],
+    /// FM index, in radians of phase deviation.
+    fm_index: f32,
+    /// Phase of the FM modulator, in cycles.
+    fm_phase: f32,
+    /// Index of the partial at the harmonic modulating the others, if there is one.
+    fm_partial: Option<usize>,
+    /// Play the polyBLEP saw of [`OscillatorType::Classic`] from the first phasor instead of
+    /// summing the partials.
+    classic: bool,
 }
 
 impl Oscillator {
     pub fn new(samplerate: f32) -> Self {
         Self {
-            phase_offset: 0.,
             samplerate,
             gains: array::from_fn(|_| f32x8::splat(0.)),
             phasors: array::from_fn(|_| Phasor8::new(f32x8::splat(samplerate), f32x8::splat(0.))),
+            phase_offsets: array::from_fn(|_| f32x8::splat(0.)),
+            shaping: array::from_fn(|_| f32x8::splat(1.)),
+            tilt: 0.,
+            filter: None,
+            formant: None,
+            fm: None,
+            fm_scale: array::from_fn(|_| f32x8::splat(0.)),
+            fm_index: 0.,
+            fm_phase: 0.,
+            fm_partial: None,
+            classic: false,
         }
     }
+
+    /// Build an oscillator from a function returning the `(gain, frequency)` pair of each partial.
     pub fn from_bode(samplerate: f32, f: impl Fn(usize) -> (f32, f32)) -> Self {
         let mut this = Self::new(samplerate);
 
         let samplerate = f32x8::splat(samplerate);
-        let mut gains = [0.0; 1024];
-        let mut frequencies = [0.0; 1024];
+        let mut gains = [0.0; MAX_HARMONICS];
+        let mut frequencies = [0.0; MAX_HARMONICS];
 
-        for i in 0..1024 {
-            let (f, p) = f(i);
-            gains[i] = f;
-            frequencies[i] = p;
+        for i in 0..MAX_HARMONICS {
+            let (gain, freq) = f(i);
+            gains[i] = gain;
+            frequencies[i] = freq;
         }
 
-        for (i, (freqs, phases)) in gains.chunks(8).zip(frequencies.chunks(8)).enumerate() {
-            this.gains[i] = f32x8::from_slice(freqs);
-            this.phasors[i] = Phasor8::new(samplerate, f32x8::from_slice(phases));
+        for (i, (gains, freqs)) in gains.chunks(8).zip(frequencies.chunks(8)).enumerate() {
+            this.gains[i] = f32x8::from_slice(gains);
+            this.phasors[i] = Phasor8::new(samplerate, f32x8::from_slice(freqs));
         }
 
         this
     }
+
+    /// Build an oscillator from a function returning the amplitude and phase, in radians, of each
+    /// harmonic of `hz`, counting from 1 for the fundamental.
+    pub fn from_spectrum(samplerate: f32, hz: f32, f: impl Fn(usize) -> (f32, f32)) -> Self {
+        let mut this = Self::new(samplerate);
+        this.set_spectrum(hz, f);
+        this
+    }
+
+    /// Build an oscillator from the amplitudes and phases of a harmonic table.
+    pub fn from_harmonics(samplerate: f32, hz: f32, table: &HarmonicTable) -> Self {
+        Self::from_spectrum(samplerate, hz, |n| {
+            let harmonic = table.get(n);
+            (harmonic.amplitude, harmonic.phase)
+        })
+    }
+
+    /// Build the spectrum of `shape` hard-synced at `ratio` times `hz`.
+    pub fn synced(shape: SyncShape, samplerate: f32, hz: f32, ratio: f32) -> Self {
+        let mut this = Self::new(samplerate);
+        this.set_sync(shape, hz, ratio);
+        this
+    }
+
+    /// Build an oscillator of type `ty`, reading the partials from `table` for
+    /// [`OscillatorType::Table`] and [`OscillatorType::Wavetable`].
+    pub fn from_type(
+        ty: OscillatorType,
+        samplerate: f32,
+        hz: f32,
+        table: &HarmonicTable,
+    ) -> Self {
+        match ty {
+            OscillatorType::Sine => Self::sine(samplerate, hz),
+            OscillatorType::Triangle => Self::triangle(samplerate, hz),
+            OscillatorType::Saw => Self::saw(samplerate, hz),
+            OscillatorType::Square => Self::square(samplerate, hz),
+            OscillatorType::Table | OscillatorType::Wavetable => {
+                Self::from_harmonics(samplerate, hz, table)
+            }
+            OscillatorType::Modal => Self::new(samplerate),
+            OscillatorType::Classic => Self::classic(samplerate, hz),
+        }
+    }
+
     pub fn sine(samplerate: f32, hz: f32) -> Self {
         let mut this = Self::new(samplerate);
         let mask = mask32x8::from_array([true, false, false, false, false, false, false, false]);
@@ -55,62 +166,288 @@ impl Oscillator {
 
     pub fn triangle(samplerate: f32, hz: f32) -> Self {
         Self::from_bode(samplerate, |i| {
-            let i = i + 1;
-            let gain = f32::recip(i.pow(2) as f32);
-            let freq = hz * (2.0 * i as f32 - 1.0);
-            (gain, freq)
+            let n = 2.0 * i as f32 + 1.0;
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let gain = sign * 8.0 / (PI * n).powi(2);
+            (gain, hz * n)
         })
     }
 
     pub fn square(samplerate: f32, hz: f32) -> Self {
         Self::from_bode(samplerate, |i| {
-            let i = i + 1;
-            let inc = 2.0 * i as f32 - 1.;
-            let gain = inc.recip();
-            let freq = hz * inc;
-            (gain, freq)
+            let n = 2.0 * i as f32 + 1.0;
+            (4.0 / (PI * n), hz * n)
         })
     }
 
     pub fn saw(samplerate: f32, hz: f32) -> Self {
-        Self::from_bode(samplerate, |i| (f32::recip(1.0 + i as f32), hz * i as f32))
+        // Negative gains so that the waveform ramps up, like the naive `2 * phase - 1` saw
+        Self::from_bode(samplerate, |i| {
+            let n = i as f32 + 1.0;
+            (-2.0 / (PI * n), hz * n)
+        })
     }
 
-    pub fn sample(&mut self) -> f32 {
-        let phasor = &mut self.phasors[0];
-        let phase = phasor.inc(u8x8::splat(1))[0];
-        phase * 2. - 1. - poly_blep(phase, self.phasors[0].step()[0])
+    /// The polyBLEP saw of [`OscillatorType::Classic`]. The partials are those of the saw, so that
+    /// it can still be morphed from, but they aren't played.
+    pub fn classic(samplerate: f32, hz: f32) -> Self {
+        let mut this = Self::saw(samplerate, hz);
+        this.classic = true;
+        this
     }
 
+    /// Replace the gain and frequency of each chunk of partials with the result of `f`, keeping
+    /// the phases running. The shaping is recomputed on the next call to [`Self::set_shaping`].
+    pub fn set_partials(&mut self, f: impl Fn(usize) -> (f32x8, f32x8)) {
+        for (i, (gain, phasor)) in self.gains.iter_mut().zip(self.phasors.iter_mut()).enumerate() {
+            (*gain, phasor.hz) = f(i);
+        }
+        self.tilt = f32::NAN;
+        if self.fm.is_some() {
+            self.update_fm_scale();
+        }
+    }
+
+    /// Replace the partials with the harmonics of `hz`, with amplitudes and phases given by `f`
+    /// like in [`Self::from_spectrum`]. The phasors keep running, except for the chunks that were
+    /// silent until now: those weren't advanced, so they are realigned on the first phasor to keep
+    /// the harmonics in phase with each other.
+    pub fn set_spectrum(&mut self, hz: f32, f: impl Fn(usize) -> (f32, f32)) {
+        let spectrum: [[(f32, f32); 8]; 128] =
+            array::from_fn(|i| array::from_fn(|j| f(8 * i + j + 1)));
+        let silent: [bool; 128] =
+            array::from_fn(|i| self.gains[i].simd_eq(f32x8::splat(0.)).all());
+        let harmonics = |i: usize| f32x8::from_array(array::from_fn(|j| (8 * i + j + 1) as f32));
+        self.set_partials(|i| {
+            let gains = f32x8::from_array(spectrum[i].map(|(amplitude, _)| amplitude));
+            (gains, harmonics(i) * f32x8::splat(hz))
+        });
+        let fundamental = f32x8::splat(self.phasors[0].phase[0]);
+        for (i, (offset, phasor)) in self
+            .phase_offsets
+            .iter_mut()
+            .zip(self.phasors.iter_mut())
+            .enumerate()
+        {
+            // The phasors count in cycles rather than radians
+            *offset = f32x8::from_array(spectrum[i].map(|(_, phase)| phase)) / TAU;
+            if silent[i] {
+                phasor.set_phase(harmonics(i) * fundamental);
+            }
+        }
+        self.align_fm_modulator();
+    }
+
+    /// Set the phase of each phasor, in cycles, to `f` of its frequency. The phases from the
+    /// spectrum, if any, stay on top of these.
+    pub fn set_phases(&mut self, mut f: impl FnMut(f32) -> f32) {
+        for phasor in &mut self.phasors {
+            let phase = phasor.hz.to_array().map(&mut f);
+            phasor.set_phase(f32x8::from_array(phase));
+        }
+        self.align_fm_modulator();
+    }
+
+    /// Reshape the partials into the spectrum of `shape` hard-synced at `ratio` times `hz`. This is
+    /// meant to be called at block rate while sweeping the ratio. Only the harmonics that can end
+    /// up below Nyquist are computed, leaving room for the pitch to be modulated down by the full
+    /// range of [`ModDestination::Pitch`].
+    pub fn set_sync(&mut self, shape: SyncShape, hz: f32, ratio: f32) {
+        let headroom = (ModDestination::Pitch.range() / 12.).exp2();
+        let max_harmonic = (headroom * self.samplerate / 2. / hz) as usize;
+        self.set_spectrum(hz, |n| {
+            if n <= max_harmonic {
+                shape.harmonic(ratio, n)
+            } else {
+                (0., 0.)
+            }
+        });
+    }
+
+    /// Set the frequency modulation of the partials, recomputing the per-partial index multipliers
+    /// when it changes. This is meant to be called at block rate.
+    pub fn set_fm(&mut self, fm: Option<Fm>) {
+        if fm == self.fm {
+            return;
+        }
+        self.fm = fm;
+        self.update_fm_scale();
+        self.align_fm_modulator();
+    }
+
+    /// Set the FM index, in radians. Unlike the rest of the FM settings, this is cheap to change
+    /// every sample.
+    #[inline]
+    pub fn set_fm_index(&mut self, index: f32) {
+        self.fm_index = index;
+    }
+
+    fn update_fm_scale(&mut self) {
+        self.fm_partial = None;
+        let Some(fm) = self.fm else {
+            self.fm_scale = array::from_fn(|_| f32x8::splat(0.));
+            return;
+        };
+        let f0 = self.phasors[0].hz[0];
+        for (scale, phasor) in self.fm_scale.iter_mut().zip(self.phasors.iter()) {
+            *scale = f32x8::from_array(
+                phasor
+                    .hz
+                    .to_array()
+                    .map(|hz| if hz > 0. { fm.index_scale(hz, f0) } else { 0. }),
+            );
+        }
+        // The modulating partial would otherwise modulate itself
+        if let FmSource::Partial(n) = fm.source {
+            let n = n as f32;
+            self.fm_partial = (0..MAX_HARMONICS).find(|i| {
+                let hz = self.phasors[i / 8].hz[i % 8];
+                hz > 0. && (hz / f0 - n).abs() < 1e-3 * n
+            });
+            if let Some(i) = self.fm_partial {
+                self.fm_scale[i / 8][i % 8] = 0.;
+            }
+        }
+    }
+
+    /// Start the modulator of [`FmSource::Partial`] at the phase of the partial it stands for, so
+    /// that it stays in phase with it.
+    fn align_fm_modulator(&mut self) {
+        if let Some(i) = self.fm_partial {
+            let phase = self.phasors[i / 8].phase[i % 8] + self.phase_offsets[i / 8][i % 8];
+            self.fm_phase = phase.rem_euclid(1.);
+        }
+    }
+
+    /// Advance the FM modulator, returning the phase offset to scale by `fm_scale`, in radians.
     #[inline(always)]
-    #[cfg(never)]
-    pub fn sample(&mut self) -> f32 {
-        let nyquist = self.samplerate / 2.0;
-        let nyquist = f32x8::splat(nyquist);
-        let phase_offset = f32x8::splat(self.phase_offset);
-        let mut total_gain = 0.;
+    fn fm_modulation(&mut self, pitch: f32) -> f32x8 {
+        // The partial source runs on its own rather than reading the phasor of its partial, which
+        // doesn't advance while it is silent or above Nyquist
+        let ratio = match self.fm.map(|fm| fm.source) {
+            None => return f32x8::splat(0.),
+            Some(FmSource::Modulator { ratio }) => ratio,
+            Some(FmSource::Partial(n)) => n as f32,
+        };
+        let step = ratio * self.phasors[0].hz[0] * pitch / self.samplerate;
+        self.fm_phase = (self.fm_phase + step).fract();
+        f32x8::splat(self.fm_index * (std::f32::consts::TAU * self.fm_phase).sin())
+    }
+
+    /// Scale the partials by a spectral tilt of `db_per_oct` dB per octave above the first partial,
+    /// and by the responses of the spectral `filter` and `formant` envelope if there are any. The
+    /// gain multipliers are only recomputed when any of them changes, so this is meant to be called
+    /// at block rate.
+    pub fn set_shaping(
+        &mut self,
+        db_per_oct: f32,
+        filter: Option<SpectralFilter>,
+        formant: Option<FormantFilter>,
+    ) {
+        if db_per_oct == self.tilt && filter == self.filter && formant == self.formant {
+            return;
+        }
+        self.tilt = db_per_oct;
+        self.filter = filter;
+        self.formant = formant;
+
+        let f0 = self.phasors[0].hz[0];
+        let exponent = db_per_oct / (20. * 2f32.log10());
+        for ((shape, gain), phasor) in self
+            .shaping
+            .iter_mut()
+            .zip(self.gains.iter())
+            .zip(self.phasors.iter())
+        {
+            if gain.simd_eq(f32x8::splat(0.)).all() {
+                continue;
+            }
+            *shape = f32x8::from_array(phasor.hz.to_array().map(|hz| {
+                if hz > 0. {
+                    let response = filter.map_or(1., |filter| filter.response(hz))
+                        * formant.map_or(1., |formant| formant.response(hz));
+                    (hz / f0).powf(exponent) * response
+                } else {
+                    1.
+                }
+            }));
+        }
+    }
+
+    /// Render the next sample of the partial bank, with all frequencies multiplied by `pitch`.
+    /// Partials that would end up above Nyquist are skipped. The FM sidebands aren't, so high
+    /// indices can alias.
+    #[inline(always)]
+    pub fn sample(&mut self, pitch: f32) -> f32 {
+        if self.classic {
+            return self.sample_classic(pitch);
+        }
+        let fm = self.fm_modulation(pitch);
+        let nyquist = f32x8::splat(self.samplerate / 2.0);
+        let pitch = f32x8::splat(pitch);
+        let zero = f32x8::splat(0.);
         self.gains
             .iter()
-            .copied()
+            .zip(self.shaping.iter())
+            .zip(self.fm_scale.iter())
+            .zip(self.phase_offsets.iter())
             .zip(self.phasors.iter_mut())
-            .filter(|(g, p)| {
-                g.simd_ge(f32x8::splat(f32::EPSILON)).any() && p.hz.simd_lt(nyquist).any()
+            .fold(zero, |acc, ((((gain, shape), fm_scale), offset), phasor)| {
+                let mask = gain.simd_ne(zero) & (phasor.hz * pitch).simd_lt(nyquist);
+                if !mask.any() {
+                    return acc;
+                }
+                let phase = phasor.advance(pitch);
+                let y = (TAU * (phase + *offset) + *fm_scale * fm).sin();
+                acc + mask.select(*gain * *shape * y, zero)
             })
-            .map(|(gain, phase)| {
-                let mask = gain.simd_ge(f32x8::splat(f32::EPSILON)) & phase.hz.simd_lt(nyquist);
-                let phase =
-                    phase.inc(mask.select(u32x8::splat(1), u32x8::splat(0)).cast())/* + phase_offset*/;
-                let r = gain * (TAU * phase).sin();
-                total_gain += mask.select(gain, f32x8::splat(0.)).reduce_sum();
-                mask.select(r, f32x8::splat(0.))
-            })
-            .reduce(|a, b| a + b)
-            .map(|s| s.reduce_sum())
-            .unwrap_or_default()
-            / total_gain
+            .reduce_sum()
+    }
+
+    /// Render the next sample like [`Self::sample`], but as two separate sums: one of the partials
+    /// up to harmonic `split` of the first partial, and one of the partials above it.
+    #[inline(always)]
+    pub fn sample_split(&mut self, pitch: f32, split: f32) -> [f32; 2] {
+        if self.classic {
+            return [self.sample_classic(pitch), 0.];
+        }
+        let fm = self.fm_modulation(pitch);
+        let nyquist = f32x8::splat(self.samplerate / 2.0);
+        let threshold = f32x8::splat((split + 0.5) * self.phasors[0].hz[0]);
+        let pitch = f32x8::splat(pitch);
+        let zero = f32x8::splat(0.);
+        let (low, high) = self
+            .gains
+            .iter()
+            .zip(self.shaping.iter())
+            .zip(self.fm_scale.iter())
+            .zip(self.phase_offsets.iter())
+            .zip(self.phasors.iter_mut())
+            .fold((zero, zero), |(low, high), ((((gain, shape), fm_scale), offset), phasor)| {
+                let mask = gain.simd_ne(zero) & (phasor.hz * pitch).simd_lt(nyquist);
+                if !mask.any() {
+                    return (low, high);
+                }
+                let is_low = phasor.hz.simd_lt(threshold);
+                let phase = phasor.advance(pitch);
+                let y = (TAU * (phase + *offset) + *fm_scale * fm).sin();
+                let y = mask.select(*gain * *shape * y, zero);
+                (low + is_low.select(y, zero), high + is_low.select(zero, y))
+            });
+        [low.reduce_sum(), high.reduce_sum()]
+    }
+
+    /// Render the next sample of the polyBLEP saw, following the first partial's phase.
+    #[inline]
+    fn sample_classic(&mut self, pitch: f32) -> f32 {
+        let phasor = &mut self.phasors[0];
+        let phase = phasor.advance(f32x8::splat(pitch))[0];
+        2. * phase - 1. - poly_blep(phase, pitch * phasor.step()[0])
     }
 }
 
+/// Polynomial correction of the discontinuity of a naive saw at phase `t`, with a phase increment
+/// of `dt` per sample.
 fn poly_blep(t: f32, dt: f32) -> f32 {
     if t < dt {
         let t = t / dt;
@@ -118,5 +455,167 @@ fn poly_blep(t: f32, dt: f32) -> f32 {
     } else if t > 1. - dt {
         let t = (t - 1.) / dt;
         t + t + t * t + 1.
-    } else { 0. }
-}
\ No newline at end of file
+    } else {
+        0.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::Oscillator;
+    use crate::fm::{Fm, FmSource};
+    use crate::harmonics::{Harmonic, HarmonicTable};
+    use crate::spectral::{SpectralFilter, SpectralShape};
+    use crate::sync::SyncShape;
+
+    #[test]
+    fn classic_saw_ramps_up() {
+        let mut osc = Oscillator::classic(48e3, 100.);
+        let step = 100. / 48e3;
+        for n in 1..=960 {
+            let y = osc.sample(1.);
+            let phase = (n as f32 * step).fract();
+            // Away from the corrected discontinuity, this is the naive saw
+            if (0.01..0.99).contains(&phase) {
+                approx::assert_abs_diff_eq!(2. * phase - 1., y, epsilon = 1e-3);
+            }
+        }
+        let mut split = osc;
+        let [low, high] = split.sample_split(1., 4.);
+        approx::assert_abs_diff_eq!(osc.sample(1.), low);
+        assert_eq!(0., high);
+    }
+
+    #[test]
+    fn split_partials_sum_to_full_bank() {
+        let mut full = Oscillator::saw(48e3, 110.);
+        let mut split = full;
+        let mut low_only = Oscillator::from_bode(48e3, |i| {
+            let n = i as f32 + 1.0;
+            let gain = if n <= 4. { -2.0 / (std::f32::consts::PI * n) } else { 0. };
+            (gain, 110. * n)
+        });
+        for _ in 0..256 {
+            let y = full.sample(1.);
+            let [low, high] = split.sample_split(1., 4.);
+            approx::assert_abs_diff_eq!(y, low + high, epsilon = 1e-4);
+            approx::assert_abs_diff_eq!(low_only.sample(1.), low, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn harmonic_table_matches_saw() {
+        let table = HarmonicTable(
+            (1..=1024)
+                .map(|n| Harmonic {
+                    amplitude: 2.0 / (std::f32::consts::PI * n as f32),
+                    phase: std::f32::consts::PI,
+                })
+                .collect(),
+        );
+        let mut saw = Oscillator::saw(48e3, 110.);
+        let mut osc = Oscillator::from_harmonics(48e3, 110., &table);
+        for _ in 0..256 {
+            approx::assert_abs_diff_eq!(saw.sample(1.), osc.sample(1.), epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn global_fm_matches_closed_form() {
+        let (fs, hz, index) = (48e3, 440., 2.);
+        let mut osc = Oscillator::sine(fs, hz);
+        osc.set_fm(Some(Fm {
+            source: FmSource::Modulator { ratio: 1. },
+            scaling: 0.,
+        }));
+        osc.set_fm_index(index);
+        for i in 1..=256 {
+            let x = TAU * (hz * i as f32 / fs).fract();
+            let expected = (x + index * x.sin()).sin();
+            approx::assert_abs_diff_eq!(expected, osc.sample(1.), epsilon = 1e-3);
+        }
+    }
+
+    #[test]
+    fn partial_fm_leaves_source_unmodulated() {
+        let mut osc = Oscillator::saw(48e3, 100.);
+        osc.set_fm(Some(Fm {
+            source: FmSource::Partial(2),
+            scaling: 1.,
+        }));
+        assert_eq!([1., 0., 3., 4.], osc.fm_scale[0].to_array()[..4]);
+
+        // Without an index, FM changes nothing
+        let mut plain = Oscillator::saw(48e3, 100.);
+        for _ in 0..64 {
+            assert_eq!(plain.sample(1.), osc.sample(1.));
+        }
+    }
+
+    #[test]
+    fn partial_fm_source_is_a_harmonic_number() {
+        // The sine has no second harmonic, but it still gets modulated by one
+        let (fs, hz, index) = (48e3, 440., 2.);
+        let mut osc = Oscillator::sine(fs, hz);
+        osc.set_fm(Some(Fm {
+            source: FmSource::Partial(2),
+            scaling: 0.,
+        }));
+        osc.set_fm_index(index);
+        assert_eq!(1., osc.fm_scale[0][0]);
+        for i in 1..=256 {
+            let x = TAU * (hz * i as f32 / fs).fract();
+            let expected = (x + index * (2. * x).sin()).sin();
+            approx::assert_abs_diff_eq!(expected, osc.sample(1.), epsilon = 1e-3);
+        }
+
+        // The square only has odd harmonics, so the third one is its second partial
+        let mut osc = Oscillator::square(fs, hz);
+        osc.set_phases(|hz| hz / 1e4);
+        osc.set_fm(Some(Fm {
+            source: FmSource::Partial(3),
+            scaling: 0.,
+        }));
+        assert_eq!([1., 0., 1.], osc.fm_scale[0].to_array()[..3]);
+        for _ in 0..256 {
+            osc.sample(1.);
+        }
+        approx::assert_abs_diff_eq!(osc.phasors[0].phase[1], osc.fm_phase, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn sync_keeps_phases_and_stops_at_nyquist() {
+        let (fs, hz) = (48e3, 440.);
+        let mut osc = Oscillator::saw(fs, hz);
+        osc.set_phases(|hz| (hz / 1e3).fract());
+        let before = osc.phasors.map(|phasor| phasor.phase);
+        osc.set_sync(SyncShape::Saw, hz, 2.5);
+        assert_eq!(before, osc.phasors.map(|phasor| phasor.phase));
+
+        // Two octaves of headroom below the note
+        let max_harmonic = (4. * fs / 2. / hz) as usize;
+        let gains: Vec<f32> = osc.gains.iter().flat_map(|g| g.to_array()).collect();
+        assert!(gains[..max_harmonic].iter().all(|&gain| gain != 0.));
+        assert!(gains[max_harmonic..].iter().all(|&gain| gain == 0.));
+    }
+
+    #[test]
+    fn spectral_filter_scales_partials() {
+        let mut osc = Oscillator::saw(48e3, 100.);
+        let filter = SpectralFilter {
+            shape: SpectralShape::Lowpass,
+            fc: 1e3,
+            q: 0.,
+        };
+        osc.set_shaping(0., Some(filter), None);
+        for (i, hz) in [(0, 100.), (9, 1e3), (99, 10e3)] {
+            let shape = osc.shaping[i / 8][i % 8];
+            approx::assert_abs_diff_eq!(filter.response(hz), shape, epsilon = 1e-6);
+        }
+
+        osc.set_shaping(0., None, None);
+        assert!(osc.shaping.iter().all(|shape| shape.to_array() == [1.; 8]));
+    }
+}
@@ -0,0 +1,322 @@
+use std::f32::consts::PI;
+
+use nih_plug::prelude::Enum;
+
+/// Number of taps in each branch of the halfband filters. The full filter has `4 * HALF_TAPS - 1`
+/// taps, of which all the ones at an even distance from the center (except the center) are zero.
+const HALF_TAPS: usize = 12;
+/// Maximum number of halfband stages, giving 8x oversampling.
+const MAX_STAGES: usize = 3;
+/// Delay introduced by a single halfband filter, in samples at its higher rate.
+const STAGE_LATENCY: usize = 2 * HALF_TAPS - 1;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversamplingFactor {
+    #[name = "1x"]
+    X1,
+    #[name = "2x"]
+    X2,
+    #[name = "4x"]
+    X4,
+    #[name = "8x"]
+    X8,
+}
+
+impl OversamplingFactor {
+    /// Number of halfband stages needed for this factor.
+    pub const fn stages(self) -> usize {
+        match self {
+            Self::X1 => 0,
+            Self::X2 => 1,
+            Self::X4 => 2,
+            Self::X8 => 3,
+        }
+    }
+
+    pub const fn ratio(self) -> usize {
+        1 << self.stages()
+    }
+
+    /// Delay added by a round trip through the [`Oversampler`], in samples at the base rate.
+    pub fn latency(self) -> usize {
+        (self.filter_latency() + self.ratio() - 1) / self.ratio()
+    }
+
+    /// Delay added by a round trip through the halfband filters alone, in samples at the
+    /// oversampled rate. This is generally not a whole number of samples at the base rate.
+    fn filter_latency(self) -> usize {
+        // Each stage delays by `STAGE_LATENCY` samples at its higher rate on the way up, and one
+        // sample less on the way down as the decimator outputs the later sample of each pair
+        let stages = self.stages();
+        (1..=stages)
+            .map(|s| (2 * STAGE_LATENCY - 1) << (stages - s))
+            .sum()
+    }
+
+    /// Delay added at the oversampled rate to round the latency up to whole samples at the base
+    /// rate.
+    fn padding(self) -> usize {
+        self.latency() * self.ratio() - self.filter_latency()
+    }
+}
+
+/// Coefficients of the non-trivial branch of the halfband lowpass: a Blackman-Harris windowed sinc
+/// cut at a quarter of the (higher) sample rate, with the taps on even offsets from the center
+/// removed as they are zero. The center tap is 0.5.
+fn halfband_coefficients() -> [f32; 2 * HALF_TAPS] {
+    let len = 4 * HALF_TAPS - 1;
+    let center = (len / 2) as f32;
+    let mut coeffs = [0.; 2 * HALF_TAPS];
+    for (j, c) in coeffs.iter_mut().enumerate() {
+        let i = 2 * j;
+        let t = i as f32 - center;
+        let sinc = (PI * t / 2.).sin() / (PI * t);
+        let phase = 2. * PI * i as f32 / (len - 1) as f32;
+        let window = 0.35875 - 0.48829 * phase.cos() + 0.14128 * (2. * phase).cos()
+            - 0.01168 * (3. * phase).cos();
+        *c = sinc * window;
+    }
+    // Normalize for unity gain at DC, which the delay branch contributes half of
+    let sum: f32 = coeffs.iter().sum();
+    coeffs.map(|c| c * 0.5 / sum)
+}
+
+/// One halfband stage of the polyphase interpolator and decimator.
+///
+/// Both work on the two polyphase branches of the filter separately: the branch of non-zero taps
+/// runs at the lower rate, and the other branch is a pure delay.
+#[derive(Debug, Clone)]
+struct Halfband {
+    coeffs: [f32; 2 * HALF_TAPS],
+    /// Input history of the filtering branch, duplicated so that a contiguous window can always be
+    /// read starting at `pos`.
+    history: [f32; 4 * HALF_TAPS],
+    /// Input history of the delay branch.
+    delay: [f32; HALF_TAPS],
+    pos: usize,
+}
+
+impl Halfband {
+    fn new(coeffs: [f32; 2 * HALF_TAPS]) -> Self {
+        Self {
+            coeffs,
+            history: [0.; 4 * HALF_TAPS],
+            delay: [0.; HALF_TAPS],
+            pos: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.history.fill(0.);
+        self.delay.fill(0.);
+        self.pos = 0;
+    }
+
+    /// Push a sample into both branches, returning the output of the filtering branch and the
+    /// delayed sample.
+    #[inline(always)]
+    fn push(&mut self, filtered: f32, delayed: f32) -> (f32, f32) {
+        const N: usize = 2 * HALF_TAPS;
+        self.pos = if self.pos == 0 { N - 1 } else { self.pos - 1 };
+        self.history[self.pos] = filtered;
+        self.history[self.pos + N] = filtered;
+        let window = &self.history[self.pos..self.pos + N];
+        let y = window.iter().zip(self.coeffs.iter()).map(|(x, c)| x * c).sum();
+
+        // The delay line shares the same write position, and the sample written `HALF_TAPS - 1`
+        // pushes ago sits right after it
+        let slot = self.pos % HALF_TAPS;
+        self.delay[slot] = delayed;
+        (y, self.delay[(slot + HALF_TAPS - 1) % HALF_TAPS])
+    }
+
+    /// Interpolate one sample into two at twice the rate.
+    #[inline(always)]
+    fn upsample(&mut self, x: f32) -> [f32; 2] {
+        let (y, d) = self.push(x, x);
+        [2. * y, d]
+    }
+
+    /// Decimate two samples at the higher rate into one.
+    #[inline(always)]
+    fn downsample(&mut self, [a, b]: [f32; 2]) -> f32 {
+        let (y, d) = self.push(b, a);
+        y + 0.5 * d
+    }
+}
+
+/// Runs a per-sample process at a multiple of the sample rate, interpolating the input and
+/// decimating the output with cascaded halfband filters.
+#[derive(Debug, Clone)]
+pub struct Oversampler {
+    factor: OversamplingFactor,
+    up: [Halfband; MAX_STAGES],
+    down: [Halfband; MAX_STAGES],
+    /// Previous oversampled block, from which the samples delayed by [`OversamplingFactor::padding`]
+    /// are taken.
+    previous: [f32; 1 << MAX_STAGES],
+}
+
+impl Oversampler {
+    pub fn new(factor: OversamplingFactor) -> Self {
+        let coeffs = halfband_coefficients();
+        Self {
+            factor,
+            up: [(); MAX_STAGES].map(|_| Halfband::new(coeffs)),
+            down: [(); MAX_STAGES].map(|_| Halfband::new(coeffs)),
+            previous: [0.; 1 << MAX_STAGES],
+        }
+    }
+
+    pub fn factor(&self) -> OversamplingFactor {
+        self.factor
+    }
+
+    /// Change the oversampling factor. A stage runs at the same rate whatever the factor, so the
+    /// stages that were already in use keep their state, and only the ones that weren't are
+    /// cleared.
+    pub fn set_factor(&mut self, factor: OversamplingFactor) {
+        if factor != self.factor {
+            let in_use = self.factor.stages();
+            for stage in self.up[in_use..].iter_mut().chain(self.down[in_use..].iter_mut()) {
+                stage.reset();
+            }
+            self.previous.fill(0.);
+            self.factor = factor;
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for stage in self.up.iter_mut().chain(self.down.iter_mut()) {
+            stage.reset();
+        }
+        self.previous.fill(0.);
+    }
+
+    /// Process one sample, running `f` on each of the oversampled samples.
+    #[inline]
+    pub fn process(&mut self, x: f32, mut f: impl FnMut(f32) -> f32) -> f32 {
+        let stages = self.factor.stages();
+        if stages == 0 {
+            return f(x);
+        }
+
+        let mut buffer = [0f32; 1 << MAX_STAGES];
+        let mut scratch = [0f32; 1 << MAX_STAGES];
+        buffer[0] = x;
+        let mut len = 1;
+        for stage in &mut self.up[..stages] {
+            for i in 0..len {
+                let [a, b] = stage.upsample(buffer[i]);
+                scratch[2 * i] = a;
+                scratch[2 * i + 1] = b;
+            }
+            len *= 2;
+            buffer[..len].copy_from_slice(&scratch[..len]);
+        }
+
+        let padding = self.factor.padding();
+        if padding > 0 {
+            scratch[..padding].copy_from_slice(&self.previous[len - padding..len]);
+            scratch[padding..len].copy_from_slice(&buffer[..len - padding]);
+            self.previous[..len].copy_from_slice(&buffer[..len]);
+            buffer[..len].copy_from_slice(&scratch[..len]);
+        }
+
+        for y in &mut buffer[..len] {
+            *y = f(*y);
+        }
+
+        for stage in self.down[..stages].iter_mut().rev() {
+            len /= 2;
+            for i in 0..len {
+                buffer[i] = stage.downsample([buffer[2 * i], buffer[2 * i + 1]]);
+            }
+        }
+        buffer[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::TAU;
+
+    use super::{OversamplingFactor, Oversampler};
+
+    const N: usize = 2048;
+
+    /// Power of each DFT bin of `x`, up to Nyquist.
+    fn power_spectrum(x: &[f32]) -> Vec<f64> {
+        let twiddles: Vec<(f64, f64)> = (0..N)
+            .map(|i| {
+                let w = TAU * i as f64 / N as f64;
+                (w.cos(), w.sin())
+            })
+            .collect();
+        (0..=N / 2)
+            .map(|k| {
+                let (re, im) = x.iter().enumerate().fold((0., 0.), |(re, im), (n, &x)| {
+                    let (c, s) = twiddles[k * n % N];
+                    (re + x as f64 * c, im - x as f64 * s)
+                });
+                re * re + im * im
+            })
+            .collect()
+    }
+
+    /// Ratio of the power outside of the harmonics of `bin` to the total power, in dB, after driving
+    /// a sine through `tanh` at the given oversampling factor. Only the bins below 80% of Nyquist
+    /// are considered, as the top of the spectrum falls within the transition band of the filters.
+    fn aliasing_db(factor: OversamplingFactor, bin: usize) -> f64 {
+        let mut os = Oversampler::new(factor);
+        let w = std::f32::consts::TAU * bin as f32 / N as f32;
+        let signal: Vec<f32> = (0..2 * N)
+            .map(|n| os.process((w * n as f32).sin(), |x| (8. * x).tanh()))
+            .skip(N)
+            .collect();
+        let mut spectrum = power_spectrum(&signal);
+        spectrum.truncate(N * 2 / 5);
+        let total: f64 = spectrum.iter().sum();
+        let aliased: f64 = spectrum
+            .iter()
+            .enumerate()
+            .filter(|(k, _)| k % bin != 0)
+            .map(|(_, p)| p)
+            .sum();
+        10. * (aliased / total).log10()
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing() {
+        // Prime bins so that the aliases don't fall back onto harmonics
+        for bin in [37, 97, 211, 401] {
+            let x1 = aliasing_db(OversamplingFactor::X1, bin);
+            let x2 = aliasing_db(OversamplingFactor::X2, bin);
+            let x8 = aliasing_db(OversamplingFactor::X8, bin);
+            assert!(x2 < x1 - 6., "bin {bin}: {x1} dB at 1x, {x2} dB at 2x");
+            assert!(x8 < x1 - 30., "bin {bin}: {x1} dB at 1x, {x8} dB at 8x");
+            assert!(x8 < -60., "bin {bin}: {x8} dB at 8x");
+        }
+    }
+
+    #[test]
+    fn passes_low_frequencies_with_reported_latency() {
+        for factor in [
+            OversamplingFactor::X2,
+            OversamplingFactor::X4,
+            OversamplingFactor::X8,
+        ] {
+            let mut os = Oversampler::new(factor);
+            let latency = factor.latency() as f32;
+            // High enough that being off by a fraction of a sample fails the test
+            let w = std::f32::consts::TAU * 1e3 / 48e3;
+            for n in 0..4800 {
+                let y = os.process((w * n as f32).sin(), |x| x);
+                if n > 480 {
+                    let expected = (w * (n as f32 - latency)).sin();
+                    approx::assert_abs_diff_eq!(expected, y, epsilon = 1e-2);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+
+use crate::oscillator::Oscillator;
+
+/// Seed of the fixed phase pattern spread over the partials in [`PhaseMode::Reset`] and
+/// [`PhaseMode::FreeRun`] modes, so that every note gets the same one.
+const SPREAD_SEED: (u64, u64) = (0x5eed, 0xadd);
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhaseMode {
+    /// All partials start at phase 0 on every note, for consistent transients.
+    Reset,
+    /// Each partial starts at a random phase, drawn anew for every note.
+    Random,
+    /// The partials start where they would be if they had been running since the plugin was
+    /// reset, like the oscillators of an analog synth.
+    #[name = "Free Running"]
+    FreeRun,
+}
+
+#[derive(Params)]
+pub struct PhaseParams {
+    #[id = "phmode"]
+    pub mode: EnumParam<PhaseMode>,
+    /// How far the starting phases of the partials are spread, from 0 to a full cycle. The spread
+    /// is random in [`PhaseMode::Random`] mode, and the same pattern for every note otherwise.
+    #[id = "phspread"]
+    pub spread: FloatParam,
+}
+
+impl fmt::Debug for PhaseParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PhaseParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for PhaseParams {
+    fn default() -> Self {
+        Self {
+            mode: EnumParam::new("Phase Mode", PhaseMode::Reset),
+            spread: FloatParam::new("Phase Spread", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2)),
+        }
+    }
+}
+
+impl PhaseParams {
+    /// Set the starting phases of the partials of `osc` for a new note. `clock` is the number of
+    /// samples processed since the plugin was reset, and `prng` is only used in
+    /// [`PhaseMode::Random`] mode.
+    pub fn init(&self, osc: &mut Oscillator, prng: &mut Pcg32, clock: u64) {
+        let spread = self.spread.value();
+        let samplerate = osc.samplerate as f64;
+        match self.mode.value() {
+            PhaseMode::Reset => {
+                let mut pattern = Pcg32::new(SPREAD_SEED.0, SPREAD_SEED.1);
+                osc.set_phases(|_| spread * pattern.gen::<f32>());
+            }
+            PhaseMode::Random => osc.set_phases(|_| spread * prng.gen::<f32>()),
+            PhaseMode::FreeRun => {
+                let mut pattern = Pcg32::new(SPREAD_SEED.0, SPREAD_SEED.1);
+                osc.set_phases(|hz| {
+                    let phase = (hz as f64 * clock as f64 / samplerate).fract() as f32;
+                    phase + spread * pattern.gen::<f32>()
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nih_plug::prelude::*;
+    use rand_pcg::Pcg32;
+
+    use super::{PhaseMode, PhaseParams};
+    use crate::oscillator::Oscillator;
+
+    fn phases(osc: &Oscillator) -> Vec<f32> {
+        osc.phasors
+            .iter()
+            .flat_map(|p| p.phase.to_array())
+            .collect()
+    }
+
+    fn params(mode: PhaseMode, spread: f32) -> PhaseParams {
+        PhaseParams {
+            mode: EnumParam::new("Phase Mode", mode),
+            spread: FloatParam::new(
+                "Phase Spread",
+                spread,
+                FloatRange::Linear { min: 0., max: 1. },
+            ),
+        }
+    }
+
+    #[test]
+    fn reset_is_the_same_for_every_note() {
+        let mut prng = Pcg32::new(420, 1337);
+        let mut osc = Oscillator::saw(48e3, 100.);
+        params(PhaseMode::Reset, 0.).init(&mut osc, &mut prng, 1234);
+        assert!(phases(&osc).iter().all(|&phase| phase == 0.));
+
+        let [mut a, mut b] = [osc; 2];
+        params(PhaseMode::Reset, 0.5).init(&mut a, &mut prng, 0);
+        params(PhaseMode::Reset, 0.5).init(&mut b, &mut prng, 5678);
+        assert_eq!(phases(&a), phases(&b));
+        assert!(phases(&a).iter().any(|&phase| phase > 0.1));
+        assert!(phases(&a).iter().all(|&phase| phase < 0.5));
+    }
+
+    #[test]
+    fn random_is_seeded() {
+        let render = || {
+            let mut prng = Pcg32::new(420, 1337);
+            let [mut a, mut b] = [Oscillator::saw(48e3, 100.); 2];
+            params(PhaseMode::Random, 1.).init(&mut a, &mut prng, 0);
+            params(PhaseMode::Random, 1.).init(&mut b, &mut prng, 0);
+            (phases(&a), phases(&b))
+        };
+        let (a, b) = render();
+        assert_ne!(a, b);
+        assert_eq!((a, b), render());
+    }
+
+    #[test]
+    fn free_running_phases_follow_the_clock() {
+        let mut prng = Pcg32::new(420, 1337);
+        let mut osc = Oscillator::saw(48e3, 100.);
+        params(PhaseMode::FreeRun, 0.).init(&mut osc, &mut prng, 120);
+        let phases = phases(&osc);
+        approx::assert_abs_diff_eq!(0.25, phases[0], epsilon = 1e-6);
+        approx::assert_abs_diff_eq!(0.5, phases[1], epsilon = 1e-6);
+        approx::assert_abs_diff_eq!(0.75, phases[2], epsilon = 1e-6);
+    }
+}
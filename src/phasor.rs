@@ -26,6 +26,12 @@ impl Phasor8 {
         self.set_phase(self.phase + amt.cast::<f32>() * self.step())
     }
 
+    /// Advance the phase by `ratio` times the nominal step of each lane.
+    #[inline(always)]
+    pub fn advance(&mut self, ratio: f32x8) -> f32x8 {
+        self.set_phase(self.phase + ratio * self.step())
+    }
+
     #[inline(always)]
     pub fn set_phase(&mut self, phase: f32x8) -> f32x8 {
         let neg_mask = phase.simd_le(f32x8::default());
@@ -0,0 +1,155 @@
+use std::f32::consts::TAU;
+
+use nalgebra::{SMatrix, SVector};
+
+use crate::lpf::{FilterMode, NR_MAX_ITER, NR_TOL};
+use crate::math::{nr_solve, ScalarField};
+use crate::nonlinearity::Nonlinearity;
+
+type Y = SVector<f32, 2>;
+
+/// Two-pole Sallen-Key lowpass in the style of the Korg MS-20, where the resonance is positive
+/// feedback around the first stage going through the saturating element.
+///
+/// The linearized filter is `1 / (s² + (2 - k) s + 1)`, so it self-oscillates once `k` reaches 2.
+/// The resonance is given in the same units as the [`Ladder`](crate::lpf::Ladder) one, halved, so
+/// that both start oscillating around `q = 4`.
+#[derive(Debug, Copy, Clone)]
+pub struct SallenKey {
+    samplerate: f32,
+    g: f32,
+    k: f32,
+    y: Y,
+    mode: FilterMode,
+    nonlinearity: Nonlinearity,
+}
+
+impl SallenKey {
+    pub fn new(samplerate: f32, fc: f32, q: f32) -> Self {
+        let mut this = Self {
+            samplerate,
+            g: 0.,
+            k: 0.,
+            y: Y::zeros(),
+            mode: FilterMode::Lp2,
+            nonlinearity: Nonlinearity::Tanh,
+        };
+        this.set_fc(fc);
+        this.set_resonance(q);
+        this
+    }
+
+    /// Change the sample rate, keeping the state of the filter.
+    pub fn set_samplerate(&mut self, samplerate: f32, fc: f32) {
+        self.samplerate = samplerate;
+        self.set_fc(fc);
+    }
+
+    pub fn set_fc(&mut self, fc: f32) {
+        self.g = TAU * fc.min(self.samplerate) / self.samplerate;
+    }
+
+    pub fn set_resonance(&mut self, q: f32) {
+        self.k = q / 2.;
+    }
+
+    /// Set the output mode. This is a two-pole filter, so every lowpass and highpass mode gives the
+    /// 12 dB/oct response.
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    pub fn set_nonlinearity(&mut self, nonlinearity: Nonlinearity) {
+        self.nonlinearity = nonlinearity;
+    }
+
+    #[inline(always)]
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        let phi = Phi {
+            x,
+            g: self.g,
+            k: self.k,
+            s: self.y,
+            nl: self.nonlinearity,
+        };
+        nr_solve(&phi, &mut self.y, NR_MAX_ITER, NR_TOL);
+
+        let [y0, y1] = [self.y[0], self.y[1]];
+        let bp = y0 - y1;
+        match self.mode {
+            FilterMode::Lp1 | FilterMode::Lp2 | FilterMode::Lp3 | FilterMode::Lp4 => y1,
+            FilterMode::Hp1 | FilterMode::Hp2 | FilterMode::Hp3 | FilterMode::Hp4 => {
+                x - (2. - self.k) * bp - y1
+            }
+            FilterMode::Bp2 | FilterMode::Bp4 => 2. * bp,
+            FilterMode::Notch => x - (2. - self.k) * bp,
+        }
+    }
+}
+
+/// Backward Euler residual of `y0' = wc (x - y0 + k f(y0 - y1))`, `y1' = wc (y0 - y1)`.
+struct Phi {
+    x: f32,
+    g: f32,
+    k: f32,
+    s: Y,
+    nl: Nonlinearity,
+}
+
+impl ScalarField<f32, 2> for Phi {
+    #[inline(always)]
+    fn eval(&self, y: &Y) -> Y {
+        let f = Y::new(
+            self.x - y[0] + self.k * self.nl.f(y[0] - y[1]),
+            y[0] - y[1],
+        );
+        self.s + f * self.g - y
+    }
+
+    #[inline(always)]
+    #[rustfmt::skip]
+    fn jacobian(&self, y: &Y) -> SMatrix<f32, 2, 2> {
+        let d = self.k * self.nl.df(y[0] - y[1]);
+        SMatrix::<_, 2, 2>::new(
+            d - 1., -d,
+            1., -1.,
+        ) * self.g - SMatrix::identity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SallenKey;
+    use crate::filter::{ring_peak, sine_gain_db};
+    use crate::lpf::FilterMode;
+
+    const FS: f32 = 48e3;
+    const FC: f32 = 1e3;
+
+    fn gain_db(mut filter: SallenKey, freq: f32) -> f32 {
+        sine_gain_db(FS, freq, |x| filter.process_sample(x))
+    }
+
+    #[test]
+    fn two_pole_responses() {
+        let mut lp = SallenKey::new(FS, FC, 0.);
+        lp.set_mode(FilterMode::Lp4);
+        assert!(gain_db(lp, 62.5).abs() < 0.5);
+        approx::assert_abs_diff_eq!(12., gain_db(lp, 4e3) - gain_db(lp, 8e3), epsilon = 2.);
+
+        let mut hp = lp;
+        hp.set_mode(FilterMode::Hp2);
+        assert!(gain_db(hp, 8e3).abs() < 3.);
+        approx::assert_abs_diff_eq!(12., gain_db(hp, 125.) - gain_db(hp, 62.5), epsilon = 1.);
+    }
+
+    #[test]
+    fn self_oscillates_past_threshold() {
+        let ring = |q| {
+            let mut filter = SallenKey::new(FS, FC, q);
+            ring_peak(FS, |x| filter.process_sample(x))
+        };
+        assert!(ring(3.) < 1e-4);
+        assert!(ring(6.) > 1e-2);
+    }
+}
@@ -0,0 +1,185 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+
+use crate::filter::MAX_ENV_OCTAVES;
+
+/// Frequency ratios to the cutoff and gains of the peaks of the formant curve, spaced like the
+/// formants of a neutral vowel.
+const FORMANTS: [(f32, f32); 3] = [(1., 1.), (3., 0.5), (5., 0.25)];
+/// Quality factor of the formant peaks without resonance.
+const FORMANT_Q: f32 = 5.;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpectralShape {
+    Off,
+    /// 24 dB/oct lowpass.
+    Lowpass,
+    /// 24 dB/oct highpass.
+    Highpass,
+    /// 12 dB/oct bandpass, with unity gain at the cutoff.
+    Bandpass,
+    /// Peaks at the cutoff and at its 3rd and 5th harmonics.
+    Formant,
+}
+
+/// Filter applied in the frequency domain, by scaling the gain of each partial by the magnitude
+/// response of the filter at its frequency. As the partials are pure sines, this is exact: there
+/// is no aliasing, and nothing to compute per sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectralFilter {
+    pub shape: SpectralShape,
+    pub fc: f32,
+    /// Resonance, in the same units as the time-domain filters.
+    pub q: f32,
+}
+
+impl SpectralFilter {
+    /// Magnitude response of the filter at `hz`. The filter is modelled on analog prototypes, so
+    /// that the curves match the time-domain filters at low frequencies.
+    pub fn response(&self, hz: f32) -> f32 {
+        let r = hz / self.fc;
+        let q = FRAC_1_SQRT_2 * (1. + self.q);
+        match self.shape {
+            SpectralShape::Off => 1.,
+            SpectralShape::Lowpass => 1. / (section(r, q) * section(r, FRAC_1_SQRT_2)),
+            SpectralShape::Highpass => r.powi(4) / (section(r, q) * section(r, FRAC_1_SQRT_2)),
+            SpectralShape::Bandpass => bandpass(r, q),
+            SpectralShape::Formant => FORMANTS
+                .iter()
+                .map(|&(ratio, gain)| gain * bandpass(r / ratio, FORMANT_Q + self.q))
+                .sum(),
+        }
+    }
+}
+
+/// Magnitude of the denominator of a second-order section, `|1 - r² + j r / q|`.
+#[inline]
+fn section(r: f32, q: f32) -> f32 {
+    ((1. - r * r).powi(2) + (r / q).powi(2)).sqrt()
+}
+
+/// Second-order bandpass, normalized to unity gain at its center.
+#[inline]
+pub(crate) fn bandpass(r: f32, q: f32) -> f32 {
+    r / q / section(r, q)
+}
+
+#[derive(Params)]
+pub struct SpectralParams {
+    #[id = "shape"]
+    pub shape: EnumParam<SpectralShape>,
+    #[id = "hz"]
+    pub cutoff: FloatParam,
+    #[id = "q"]
+    pub q: FloatParam,
+    #[id = "env"]
+    pub env_octaves: FloatParam,
+    #[id = "kt"]
+    pub key_tracking: FloatParam,
+}
+
+impl fmt::Debug for SpectralParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpectralParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for SpectralParams {
+    fn default() -> Self {
+        Self {
+            shape: EnumParam::new("Spectral Filter", SpectralShape::Off),
+            cutoff: FloatParam::new(
+                "Spectral Cutoff",
+                2e3,
+                FloatRange::Skewed {
+                    min: 20.,
+                    max: 20e3,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            )
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz())
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2)),
+            q: FloatParam::new(
+                "Spectral Q",
+                0.,
+                FloatRange::Skewed {
+                    min: 0.,
+                    max: 16.,
+                    factor: FloatRange::skew_factor(-2.),
+                },
+            ),
+            env_octaves: FloatParam::new(
+                "Spectral Env Octaves",
+                0.,
+                FloatRange::Linear {
+                    min: -MAX_ENV_OCTAVES,
+                    max: MAX_ENV_OCTAVES,
+                },
+            )
+            .with_value_to_string(formatters::v2s_f32_rounded(2))
+            .with_unit(" oct"),
+            key_tracking: FloatParam::new(
+                "Spectral Key Tracking",
+                0.,
+                FloatRange::Linear { min: 0., max: 1. },
+            )
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_value_to_string(formatters::v2s_f32_percentage(2)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SpectralFilter, SpectralShape};
+
+    fn db(filter: &SpectralFilter, hz: f32) -> f32 {
+        20. * filter.response(hz).log10()
+    }
+
+    #[test]
+    fn responses() {
+        let filter = |shape, q| SpectralFilter { shape, fc: 1e3, q };
+
+        let lp = filter(SpectralShape::Lowpass, 0.);
+        approx::assert_abs_diff_eq!(0., db(&lp, 10.), epsilon = 1e-3);
+        approx::assert_abs_diff_eq!(-6.02, db(&lp, 1e3), epsilon = 1e-2);
+        approx::assert_abs_diff_eq!(-80., db(&lp, 10e3), epsilon = 0.1);
+
+        let hp = filter(SpectralShape::Highpass, 0.);
+        approx::assert_abs_diff_eq!(0., db(&hp, 100e3), epsilon = 1e-3);
+        approx::assert_abs_diff_eq!(-80., db(&hp, 100.), epsilon = 0.1);
+
+        for q in [1., 4., 16.] {
+            let bp = filter(SpectralShape::Bandpass, q);
+            approx::assert_abs_diff_eq!(1., bp.response(1e3), epsilon = 1e-6);
+            assert!(bp.response(500.) < 0.5 && bp.response(2e3) < 0.5);
+        }
+
+        // The peaks overlap a little, so only check that they stand out and decrease
+        let formant = filter(SpectralShape::Formant, 0.);
+        let [f1, f2, f3] = [1e3, 3e3, 5e3].map(|hz| formant.response(hz));
+        assert!(f1 > f2 && f2 > f3);
+        for (hz, peak) in [(2e3, f2), (4e3, f3), (6e3, f3)] {
+            assert!(formant.response(hz) < peak, "{hz} Hz");
+        }
+    }
+
+    #[test]
+    fn resonance_raises_peak() {
+        let mut peak = 0.;
+        for q in [0., 1., 4., 16.] {
+            let lp = SpectralFilter {
+                shape: SpectralShape::Lowpass,
+                fc: 1e3,
+                q,
+            };
+            let gain = lp.response(1e3);
+            assert!(gain > peak, "q = {q}: {gain} <= {peak}");
+            peak = gain;
+        }
+    }
+}
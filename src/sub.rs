@@ -0,0 +1,168 @@
+use std::f32::consts::TAU;
+use std::fmt;
+use std::fmt::Formatter;
+
+use nih_plug::prelude::*;
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubShape {
+    Sine,
+    Square,
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubOctave {
+    #[name = "-1 Oct"]
+    One,
+    #[name = "-2 Oct"]
+    Two,
+}
+
+impl SubOctave {
+    /// Frequency of the sub oscillator relative to the note's.
+    pub fn ratio(self) -> f32 {
+        match self {
+            Self::One => 0.5,
+            Self::Two => 0.25,
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct SubParams {
+    #[id = "subshape"]
+    pub shape: EnumParam<SubShape>,
+    #[id = "suboct"]
+    pub octave: EnumParam<SubOctave>,
+    #[id = "sublevel"]
+    pub level: FloatParam,
+}
+
+impl fmt::Debug for SubParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for SubParams {
+    fn default() -> Self {
+        Self {
+            shape: EnumParam::new("Sub Shape", SubShape::Sine),
+            octave: EnumParam::new("Sub Octave", SubOctave::One),
+            level: FloatParam::new("Sub Level", 0., FloatRange::Linear { min: 0., max: 1. })
+                .with_string_to_value(formatters::s2v_f32_percentage())
+                .with_value_to_string(formatters::v2s_f32_percentage(2))
+                .with_smoother(SmoothingStyle::Linear(10.)),
+        }
+    }
+}
+
+/// A sine or square oscillator an octave or two below the note, starting at phase 0 on note-on.
+/// The square is antialiased with PolyBLEPs, which is plenty at these frequencies.
+#[derive(Debug, Clone)]
+pub struct SubOscillator {
+    samplerate: f32,
+    /// Frequency of the note, in Hz.
+    hz: f32,
+    phase: f32,
+}
+
+impl SubOscillator {
+    pub fn new(samplerate: f32, hz: f32) -> Self {
+        Self {
+            samplerate,
+            hz,
+            phase: 0.,
+        }
+    }
+
+    #[inline]
+    fn step(&self, octave: SubOctave, pitch: f32) -> f32 {
+        (self.hz * octave.ratio() * pitch / self.samplerate).min(0.5)
+    }
+
+    /// Advance the phase by a sample without rendering it, so that the sub stays in phase with the
+    /// note while it is silent.
+    #[inline]
+    pub fn skip(&mut self, octave: SubOctave, pitch: f32) {
+        self.phase = (self.phase + self.step(octave, pitch)).fract();
+    }
+
+    /// Render the next sample, with the frequency multiplied by `pitch`.
+    #[inline]
+    pub fn next(&mut self, shape: SubShape, octave: SubOctave, pitch: f32) -> f32 {
+        let step = self.step(octave, pitch);
+        let phase = self.phase;
+        self.phase = (self.phase + step).fract();
+        match shape {
+            SubShape::Sine => (TAU * phase).sin(),
+            SubShape::Square => {
+                let naive = if phase < 0.5 { 1. } else { -1. };
+                naive + poly_blep(phase, step) - poly_blep((phase + 0.5).fract(), step)
+            }
+        }
+    }
+}
+
+/// Correction of a unit upward step at phase 0, for a phasor advancing by `step` per sample.
+#[inline]
+fn poly_blep(phase: f32, step: f32) -> f32 {
+    if phase < step {
+        let t = phase / step;
+        2. * t - t * t - 1.
+    } else if phase > 1. - step {
+        let t = (phase - 1.) / step;
+        t * t + 2. * t + 1.
+    } else {
+        0.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::{SubOctave, SubOscillator, SubShape};
+
+    #[test]
+    fn sine_is_octaves_below() {
+        for (octave, ratio) in [(SubOctave::One, 0.5), (SubOctave::Two, 0.25)] {
+            let mut sub = SubOscillator::new(48e3, 440.);
+            for i in 0..480 {
+                let expected = (TAU * 440. * ratio * i as f32 / 48e3).sin();
+                let y = sub.next(SubShape::Sine, octave, 1.);
+                approx::assert_abs_diff_eq!(expected, y, epsilon = 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn square_is_band_limited() {
+        let mut sub = SubOscillator::new(48e3, 1e3);
+        let y: Vec<f32> = (0..4800)
+            .map(|_| sub.next(SubShape::Square, SubOctave::One, 1.))
+            .collect();
+        approx::assert_abs_diff_eq!(0., y.iter().sum::<f32>() / y.len() as f32, epsilon = 1e-2);
+        assert!(y.iter().all(|y| y.abs() <= 1.));
+        // The edges are smoothed over a couple of samples instead of jumping
+        let max_jump = y.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0., f32::max);
+        assert!(max_jump < 2., "Jump of {max_jump}");
+        // 500 Hz over 0.1 s
+        let crossings = y.windows(2).filter(|w| w[0] > 0. && w[1] <= 0.).count();
+        assert_eq!(50, crossings);
+    }
+
+    #[test]
+    fn skipping_keeps_phase() {
+        let mut played = SubOscillator::new(48e3, 440.);
+        let mut skipped = played.clone();
+        for _ in 0..100 {
+            played.next(SubShape::Sine, SubOctave::One, 1.5);
+            skipped.skip(SubOctave::One, 1.5);
+        }
+        assert_eq!(
+            played.next(SubShape::Sine, SubOctave::One, 1.),
+            skipped.next(SubShape::Sine, SubOctave::One, 1.)
+        );
+    }
+}
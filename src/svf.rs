@@ -0,0 +1,106 @@
+use std::f32::consts::PI;
+
+use crate::lpf::FilterMode;
+
+/// Linear zero-delay-feedback state-variable filter, discretized with the trapezoidal rule. Being
+/// linear, it is solved in closed form and never self-oscillates, making it the clean option.
+#[derive(Debug, Copy, Clone)]
+pub struct Svf {
+    samplerate: f32,
+    g: f32,
+    /// Damping, where `2r` is the bandpass gain of the feedback path.
+    r: f32,
+    s: [f32; 2],
+    mode: FilterMode,
+}
+
+impl Svf {
+    pub fn new(samplerate: f32, fc: f32, q: f32) -> Self {
+        let mut this = Self {
+            samplerate,
+            g: 0.,
+            r: 1.,
+            s: [0.; 2],
+            mode: FilterMode::Lp2,
+        };
+        this.set_fc(fc);
+        this.set_resonance(q);
+        this
+    }
+
+    /// Change the sample rate, keeping the state of the filter.
+    pub fn set_samplerate(&mut self, samplerate: f32, fc: f32) {
+        self.samplerate = samplerate;
+        self.set_fc(fc);
+    }
+
+    pub fn set_fc(&mut self, fc: f32) {
+        // Prewarped so that the cutoff lands exactly where asked
+        self.g = (PI * fc.min(0.49 * self.samplerate) / self.samplerate).tan();
+    }
+
+    /// Set the resonance over the same 0-16 range as the ladder. The peak gain at the cutoff is
+    /// `(1 + q) / 2`.
+    pub fn set_resonance(&mut self, q: f32) {
+        self.r = 1. / (1. + q);
+    }
+
+    /// Set the output mode. This is a two-pole filter, so every lowpass and highpass mode gives the
+    /// 12 dB/oct response.
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.mode = mode;
+    }
+
+    #[inline(always)]
+    pub fn process_sample(&mut self, x: f32) -> f32 {
+        let [s1, s2] = self.s;
+        let g = self.g;
+        let hp = (x - (2. * self.r + g) * s1 - s2) / (1. + 2. * self.r * g + g * g);
+        let bp = g * hp + s1;
+        let lp = g * bp + s2;
+        self.s = [g * hp + bp, g * bp + lp];
+
+        match self.mode {
+            FilterMode::Lp1 | FilterMode::Lp2 | FilterMode::Lp3 | FilterMode::Lp4 => lp,
+            FilterMode::Hp1 | FilterMode::Hp2 | FilterMode::Hp3 | FilterMode::Hp4 => hp,
+            FilterMode::Bp2 | FilterMode::Bp4 => 2. * self.r * bp,
+            FilterMode::Notch => x - 2. * self.r * bp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Svf;
+    use crate::filter::sine_gain_db;
+    use crate::lpf::FilterMode;
+
+    const FS: f32 = 48e3;
+    const FC: f32 = 1e3;
+
+    fn gain_db(mode: FilterMode, q: f32, freq: f32) -> f32 {
+        let mut filter = Svf::new(FS, FC, q);
+        filter.set_mode(mode);
+        sine_gain_db(FS, freq, |x| filter.process_sample(x))
+    }
+
+    #[test]
+    fn responses_at_cutoff() {
+        // Unresonant, the lowpass and highpass are down 6 dB at the cutoff and the bandpass peaks
+        approx::assert_abs_diff_eq!(-6., gain_db(FilterMode::Lp2, 0., FC), epsilon = 0.2);
+        approx::assert_abs_diff_eq!(-6., gain_db(FilterMode::Hp2, 0., FC), epsilon = 0.2);
+        approx::assert_abs_diff_eq!(0., gain_db(FilterMode::Bp2, 0., FC), epsilon = 0.2);
+        assert!(gain_db(FilterMode::Notch, 0., FC) < -30.);
+
+        let peak = 20. * (17f32 / 2.).log10();
+        approx::assert_abs_diff_eq!(peak, gain_db(FilterMode::Lp2, 16., FC), epsilon = 0.2);
+    }
+
+    #[test]
+    fn slopes() {
+        let lp = gain_db(FilterMode::Lp2, 0., 4e3) - gain_db(FilterMode::Lp2, 0., 8e3);
+        assert!(lp > 11., "lowpass slope {lp} dB/oct");
+        let hp = gain_db(FilterMode::Hp2, 0., 125.) - gain_db(FilterMode::Hp2, 0., 62.5);
+        approx::assert_abs_diff_eq!(12., hp, epsilon = 0.5);
+    }
+}
@@ -0,0 +1,135 @@
+use std::f64::consts::{FRAC_PI_2, TAU};
+
+use num_complex::Complex64;
+
+use crate::oscillator::OscillatorType;
+
+/// Maximum ratio of the synced oscillator's frequency to the note's.
+pub const MAX_SYNC_RATIO: f32 = 16.;
+
+/// Waveforms with a closed-form synced spectrum. They are all piecewise linear, so the spectrum
+/// follows from the jumps in value and slope of the waveform over a cycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncShape {
+    Saw,
+    Square,
+    Triangle,
+}
+
+impl SyncShape {
+    pub fn from_type(ty: OscillatorType) -> Option<Self> {
+        match ty {
+            OscillatorType::Saw => Some(Self::Saw),
+            OscillatorType::Square => Some(Self::Square),
+            OscillatorType::Triangle => Some(Self::Triangle),
+            _ => None,
+        }
+    }
+
+    /// Amplitude and phase, in radians, of harmonic `n` of the waveform running at `ratio` times
+    /// the note's frequency and restarted at the start of every cycle of the note, as the slave of
+    /// a hard-synced oscillator pair. A ratio of 1 gives back the unsynced waveform.
+    pub fn harmonic(self, ratio: f32, n: usize) -> (f32, f32) {
+        let r = ratio.max(1.) as f64;
+        let omega = TAU * n as f64;
+        let iw = Complex64::new(0., omega);
+        // Phase of the slave at the end of the cycle, in (0, 1]
+        let end = r - (r.ceil() - 1.);
+        // Step between consecutive cycles of the slave, and the first breakpoint at phase `offset`
+        // within a slave cycle
+        let w = Complex64::from_polar(1., -omega / r);
+        let at = |offset: f64| Complex64::from_polar(1., -omega * offset / r);
+        let breakpoints = |offset: f64| at(offset) * geometric(w, count(r, offset));
+        // The slave restarting at every cycle of the slave itself, not counting the one at 0
+        let restarts = breakpoints(1.);
+
+        // The Fourier coefficient of a periodic piecewise linear waveform is the sum over its
+        // breakpoints of `J / (iω) + D / (iω)²`, with J and D the jumps in value and slope
+        let c = match self {
+            Self::Saw => (-2. * end - 2. * restarts) / iw,
+            Self::Square => {
+                let reset = if end <= 0.5 { 0. } else { 2. };
+                (reset + 2. * restarts - 2. * breakpoints(0.5)) / iw
+            }
+            Self::Triangle => {
+                let (value, slope) = match end {
+                    e if e <= 0.25 => (4. * e, 4.),
+                    e if e <= 0.75 => (2. - 4. * e, -4.),
+                    e => (4. * e - 4., 4.),
+                };
+                let slopes = 4. * r * (1. - slope / 4.) - 8. * r * breakpoints(0.25)
+                    + 8. * r * breakpoints(0.75);
+                -value / iw + slopes / (iw * iw)
+            }
+        };
+        ((2. * c.norm()) as f32, (c.arg() + FRAC_PI_2) as f32)
+    }
+}
+
+/// Number of breakpoints at `offset + k` in the slave's phase, for `k >= 0`, within the cycle.
+fn count(r: f64, offset: f64) -> u32 {
+    (r - offset).ceil().max(0.) as u32
+}
+
+/// Sum of `w^k` for `k` in `0..count`.
+fn geometric(w: Complex64, count: u32) -> Complex64 {
+    if (w - 1.).norm() < 1e-9 {
+        Complex64::from(count as f64)
+    } else {
+        (1. - w.powu(count)) / (1. - w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncShape;
+    use crate::oscillator::Oscillator;
+
+    #[test]
+    fn unsynced_matches_waveforms() {
+        for (shape, mut osc) in [
+            (SyncShape::Saw, Oscillator::saw(48e3, 100.)),
+            (SyncShape::Square, Oscillator::square(48e3, 100.)),
+            (SyncShape::Triangle, Oscillator::triangle(48e3, 100.)),
+        ] {
+            let mut synced = Oscillator::synced(shape, 48e3, 100., 1.);
+            for _ in 0..128 {
+                approx::assert_abs_diff_eq!(osc.sample(1.), synced.sample(1.), epsilon = 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_naive_sync() {
+        // High enough sample rate for all the partials to be below Nyquist
+        let (fs, f0) = (1e6, 100.);
+        let period = (fs / f0) as usize;
+        let fract = |x: f32| x - x.floor();
+        type Waveform = fn(f32) -> f32;
+        let naive: [(SyncShape, Waveform); 3] = [
+            (SyncShape::Saw, |x| 2. * x - 1.),
+            (SyncShape::Square, |x| if x < 0.5 { 1. } else { -1. }),
+            (SyncShape::Triangle, |x| 1. - 4. * (x - 0.25).abs().min((x - 1.25).abs())),
+        ];
+        for (shape, f) in naive {
+            for ratio in [1.5, 2.7, 5.2] {
+                let mut osc = Oscillator::synced(shape, fs, f0, ratio);
+                let expected: Vec<f32> = (1..=period)
+                    .map(|i| f(fract(ratio * i as f32 / period as f32)))
+                    .collect();
+                let dc = expected.iter().sum::<f32>() / period as f32;
+                for (i, expected) in expected.into_iter().enumerate() {
+                    let y = osc.sample(1.);
+                    // Skip the Gibbs ripples around the discontinuities
+                    let phase = (i + 1) as f32 / period as f32;
+                    let slave = ratio * phase;
+                    let near = |x: f32| (x - x.round()).abs() < 0.02;
+                    if near(phase) || near(slave) || near(slave - 0.5) {
+                        continue;
+                    }
+                    approx::assert_abs_diff_eq!(expected - dc, y, epsilon = 0.05);
+                }
+            }
+        }
+    }
+}
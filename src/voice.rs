@@ -1,21 +1,41 @@
-use std::sync::{
-    atomic::{AtomicPtr, AtomicU64, Ordering::Relaxed},
-    Arc,
+use std::{
+    array,
+    f32::consts::{FRAC_PI_4, SQRT_2},
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, PoisonError, RwLock,
+    },
 };
 
 use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
 
-use crate::lpf::Ladder;
+use crate::filter::{Filter, FilterEnvMode, FilterParams, FilterRouting, MAX_ENV_OCTAVES};
 use crate::{
     adsr::{Adsr, AdsrParams},
-    oscillator::Oscillator,
-    tanh::TanhLut,
+    fm::{FmParams, MAX_FM_INDEX},
+    formant::{FormantFilter, FormantParams},
+    harmonics::{HarmonicTable, HarmonicTableError},
+    lfo::{Lfo, LfoMode, LfoParams, NUM_LFOS},
+    lut::db_to_gain,
+    math::{key_track, KEY_TRACK_CENTER},
+    modal::{Modal, ModalParams},
+    mod_matrix::{ModBuffers, ModDestination, ModSlotParams, ModSource, ModSources, NUM_MOD_SLOTS},
+    morph::{Morph, MorphParams, PartialSets},
+    noise::{Noise, NoiseParams},
+    oscillator::{Oscillator, OscillatorType},
+    oversampling::{OversamplingFactor, Oversampler},
+    phase::PhaseParams,
+    spectral::{SpectralFilter, SpectralParams, SpectralShape},
+    sub::{SubOscillator, SubParams},
+    sync::{SyncShape, MAX_SYNC_RATIO},
+    wavetable::WavetableParams,
+    MAX_BLOCK_SIZE,
 };
 
 static NEXT_VOICE_ID: AtomicU64 = AtomicU64::new(0);
 
-pub static TANH_LUT_PTR: AtomicPtr<TanhLut<true>> = AtomicPtr::new(std::ptr::null_mut());
-
 /// Compute a voice ID in case the host doesn't provide them. Polyphonic modulation will not work in
 /// this case, but playing notes will.
 const fn compute_fallback_voice_id(note: u8, channel: u8) -> i32 {
@@ -52,6 +72,13 @@ impl VoiceId {
     pub fn next_id() -> u64 {
         NEXT_VOICE_ID.load(Relaxed)
     }
+
+    /// Seed of the voice's own random streams, derived from the note and the `clock` it starts at
+    /// so that the voice doesn't draw from the shared generator for them.
+    pub fn seed(&self, clock: u64) -> u64 {
+        let voice = ((self.voice_id as u32 as u64) << 32) | ((self.channel as u64) << 8);
+        clock ^ voice ^ self.note as u64
+    }
 }
 
 impl PartialEq for VoiceId {
@@ -63,91 +90,280 @@ impl Eq for VoiceId {}
 
 #[derive(Debug, Params)]
 pub struct VoiceParams {
+    #[id = "osc"]
+    pub osc: EnumParam<OscillatorType>,
+
+    /// Frequency of the hard-synced waveform relative to the note's, for the waveforms that support
+    /// it. Leaving it at 1 turns sync off.
+    #[id = "sync"]
+    sync: FloatParam,
+
+    /// Spectrum of the [`OscillatorType::Table`] waveform.
+    #[persist = "harmonic-table"]
+    pub harmonics: Arc<RwLock<HarmonicTable>>,
+
     #[nested(id_prefix = "amp", group = "Amp")]
     amp: Arc<AdsrParams>,
 
     #[nested(id_prefix = "filter", group = "Filter")]
     filter: Arc<AdsrParams>,
 
-    #[id = "fhz"]
-    fhz: FloatParam,
+    #[id = "froute"]
+    froute: EnumParam<FilterRouting>,
+
+    #[id = "fsplit"]
+    fsplit: IntParam,
+
+    // Filter A keeps the IDs from when there was a single filter, so that older presets load
+    #[nested(group = "Filter A")]
+    filter_a: FilterParams,
+
+    #[nested(id_prefix = "fb", group = "Filter B")]
+    filter_b: FilterParams,
+
+    #[nested(id_prefix = "sf", group = "Spectral Filter")]
+    spectral: SpectralParams,
+
+    #[nested(group = "Formant")]
+    formant: FormantParams,
+
+    #[nested(group = "Morph")]
+    morph: MorphParams,
+
+    #[nested(group = "Wavetable")]
+    pub wavetable: WavetableParams,
 
-    #[id = "q"]
-    q: FloatParam,
+    #[nested(group = "FM")]
+    fm: FmParams,
 
-    #[id = "fmod"]
-    fmod: FloatParam,
+    #[nested(group = "Modal")]
+    modal: ModalParams,
 
-    #[id = "drive"]
-    drive: FloatParam,
+    #[nested(group = "Sub")]
+    sub: SubParams,
+
+    #[nested(group = "Noise")]
+    noise: NoiseParams,
+
+    /// Starting phases of the partials.
+    #[nested(group = "Phase")]
+    pub phase: PhaseParams,
+
+    #[nested(array, group = "LFO")]
+    pub lfo: [LfoParams; NUM_LFOS],
+
+    #[nested(array, group = "Mod Slot")]
+    mod_slots: [ModSlotParams; NUM_MOD_SLOTS],
 }
 
 impl Default for VoiceParams {
     fn default() -> Self {
         Self {
-            amp: Arc::new(AdsrParams::default()),
-            filter: Arc::new(AdsrParams::default()),
-            fhz: FloatParam::new(
-                "Filter Cutoff",
-                300.,
-                FloatRange::Skewed {
-                    min: 20.,
-                    max: 20e3,
-                    factor: FloatRange::skew_factor(-2.),
-                },
-            )
-            .with_string_to_value(formatters::s2v_f32_hz_then_khz())
-            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2))
-            .with_smoother(SmoothingStyle::Exponential(100.)),
-            q: FloatParam::new(
-                "Filter Q",
-                0.,
-                FloatRange::Skewed {
-                    min: 0.,
-                    max: 16.,
-                    factor: FloatRange::skew_factor(-2.),
-                },
-            )
-            .with_smoother(SmoothingStyle::Linear(30.)),
-            fmod: FloatParam::new(
-                "Filter Modulation",
-                3000.,
+            osc: EnumParam::new("Waveform", OscillatorType::Saw),
+            sync: FloatParam::new(
+                "Sync Ratio",
+                1.,
                 FloatRange::Skewed {
-                    min: 0.,
-                    max: 20e3,
-                    factor: FloatRange::skew_factor(-2.),
+                    min: 1.,
+                    max: MAX_SYNC_RATIO,
+                    factor: FloatRange::skew_factor(-1.5),
                 },
             )
-            .with_string_to_value(formatters::s2v_f32_hz_then_khz())
-            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2))
-            .with_smoother(SmoothingStyle::Exponential(100.)),
-            drive: FloatParam::new(
-                "Filter drive",
-                0.,
-                FloatRange::SymmetricalSkewed {
-                    min: -36.,
-                    max: 36.,
-                    center: 0.,
-                    factor: 2.,
-                },
-            )
-            .with_unit("dB")
-            .with_smoother(SmoothingStyle::Exponential(50.)),
+            .with_value_to_string(formatters::v2s_f32_rounded(2))
+            .with_smoother(SmoothingStyle::Logarithmic(20.)),
+            harmonics: Arc::new(RwLock::new(HarmonicTable::sine())),
+            amp: Arc::new(AdsrParams::default()),
+            filter: Arc::new(AdsrParams::default()),
+            froute: EnumParam::new("Filter Routing", FilterRouting::Single),
+            fsplit: IntParam::new("Split Harmonic", 4, IntRange::Linear { min: 1, max: 64 }),
+            filter_a: FilterParams::new("Filter A"),
+            filter_b: FilterParams::new("Filter B"),
+            spectral: SpectralParams::default(),
+            formant: FormantParams::default(),
+            morph: MorphParams::default(),
+            wavetable: WavetableParams::default(),
+            fm: FmParams::default(),
+            modal: ModalParams::default(),
+            sub: SubParams::default(),
+            noise: NoiseParams::default(),
+            phase: PhaseParams::default(),
+            lfo: Default::default(),
+            mod_slots: Default::default(),
+        }
+    }
+}
+
+impl VoiceParams {
+    fn filters(&self) -> [&FilterParams; 2] {
+        [&self.filter_a, &self.filter_b]
+    }
+
+    /// Replace the harmonic table with one parsed from JSON or CSV, see [`HarmonicTable::parse`].
+    /// The current table is kept if the text can't be parsed. This takes the table's lock for
+    /// writing, so it must be called from the GUI or another background thread.
+    pub fn import_harmonics(&self, text: &str) -> Result<(), HarmonicTableError> {
+        let table = HarmonicTable::parse(text)?;
+        *self.harmonics.write().unwrap_or_else(PoisonError::into_inner) = table;
+        Ok(())
+    }
+
+    pub fn export_harmonics_json(&self) -> String {
+        self.harmonics.read().unwrap_or_else(PoisonError::into_inner).to_json()
+    }
+
+    pub fn export_harmonics_csv(&self) -> String {
+        self.harmonics.read().unwrap_or_else(PoisonError::into_inner).to_csv()
+    }
+
+    /// Build an oscillator of type `ty` for a note at `hz`. The harmonic table and the wavetable
+    /// are silent while they are being replaced.
+    pub fn oscillator(&self, ty: OscillatorType, samplerate: f32, hz: f32) -> Oscillator {
+        let empty = HarmonicTable::default();
+        let harmonics = self.harmonics.try_read();
+        let wavetable = self.wavetable.table.try_read();
+        let table = match ty {
+            OscillatorType::Wavetable => wavetable
+                .as_ref()
+                .ok()
+                .and_then(|wavetable| wavetable.frame(self.wavetable.position.value())),
+            _ => harmonics.as_deref().ok(),
+        };
+        Oscillator::from_type(ty, samplerate, hz, table.unwrap_or(&empty))
+    }
+}
+
+/// Smoothed values of the parameters of one of the filters over the current block.
+#[derive(Debug, Clone)]
+struct FilterBlock {
+    fhz: [f32; MAX_BLOCK_SIZE],
+    q: [f32; MAX_BLOCK_SIZE],
+    fcomp: [f32; MAX_BLOCK_SIZE],
+    fmod: [f32; MAX_BLOCK_SIZE],
+    fmodoct: [f32; MAX_BLOCK_SIZE],
+    fkt: [f32; MAX_BLOCK_SIZE],
+    drive: [f32; MAX_BLOCK_SIZE],
+}
+
+impl Default for FilterBlock {
+    fn default() -> Self {
+        Self {
+            fhz: [0.; MAX_BLOCK_SIZE],
+            q: [0.; MAX_BLOCK_SIZE],
+            fcomp: [0.; MAX_BLOCK_SIZE],
+            fmod: [0.; MAX_BLOCK_SIZE],
+            fmodoct: [0.; MAX_BLOCK_SIZE],
+            fkt: [0.; MAX_BLOCK_SIZE],
+            drive: [0.; MAX_BLOCK_SIZE],
         }
     }
 }
 
+impl FilterBlock {
+    fn render(&mut self, params: &FilterParams, block_len: usize) {
+        params.cutoff.smoothed.next_block(&mut self.fhz, block_len);
+        params.q.smoothed.next_block(&mut self.q, block_len);
+        params.compensation.smoothed.next_block(&mut self.fcomp, block_len);
+        params.env_amount.smoothed.next_block(&mut self.fmod, block_len);
+        params.env_octaves.smoothed.next_block(&mut self.fmodoct, block_len);
+        params.key_tracking.smoothed.next_block(&mut self.fkt, block_len);
+        params.drive.smoothed.next_block(&mut self.drive, block_len);
+    }
+}
+
+/// Values shared by all voices over the current block. These are computed once per block by the
+/// plugin so that the voices don't each advance the parameter smoothers.
+#[derive(Debug, Clone)]
+pub struct BlockContext {
+    filters: [FilterBlock; 2],
+    /// Morph position of the voices without polyphonic modulation.
+    morph: [f32; MAX_BLOCK_SIZE],
+    wavetable_position: [f32; MAX_BLOCK_SIZE],
+    fm_index: [f32; MAX_BLOCK_SIZE],
+    sync: [f32; MAX_BLOCK_SIZE],
+    sub_level: [f32; MAX_BLOCK_SIZE],
+    noise_level: [f32; MAX_BLOCK_SIZE],
+    /// Values of the global LFOs, used by the LFOs set to global mode.
+    pub lfos: [[f32; MAX_BLOCK_SIZE]; NUM_LFOS],
+    pub mod_wheel: [f32; MAX_BLOCK_SIZE],
+    /// Channel pressure. Voices use the highest of this and their own polyphonic pressure.
+    pub aftertouch: [f32; MAX_BLOCK_SIZE],
+    /// Modulation applied to every voice by the macros.
+    pub macros: ModBuffers,
+    pub oversampling: OversamplingFactor,
+}
+
+impl Default for BlockContext {
+    fn default() -> Self {
+        Self {
+            filters: Default::default(),
+            morph: [0.; MAX_BLOCK_SIZE],
+            wavetable_position: [0.; MAX_BLOCK_SIZE],
+            fm_index: [0.; MAX_BLOCK_SIZE],
+            sync: [0.; MAX_BLOCK_SIZE],
+            sub_level: [0.; MAX_BLOCK_SIZE],
+            noise_level: [0.; MAX_BLOCK_SIZE],
+            lfos: [[0.; MAX_BLOCK_SIZE]; NUM_LFOS],
+            mod_wheel: [0.; MAX_BLOCK_SIZE],
+            aftertouch: [0.; MAX_BLOCK_SIZE],
+            macros: ModBuffers::default(),
+            oversampling: OversamplingFactor::X1,
+        }
+    }
+}
+
+impl BlockContext {
+    /// Render the smoothed voice parameters for the next `block_len` samples.
+    pub fn render_params(&mut self, params: &VoiceParams, block_len: usize) {
+        for (block, params) in self.filters.iter_mut().zip(params.filters()) {
+            block.render(params, block_len);
+        }
+        params.morph.position.smoothed.next_block(&mut self.morph, block_len);
+        params
+            .wavetable
+            .position
+            .smoothed
+            .next_block(&mut self.wavetable_position, block_len);
+        params.fm.index.smoothed.next_block(&mut self.fm_index, block_len);
+        params.sync.smoothed.next_block(&mut self.sync, block_len);
+        params.sub.level.smoothed.next_block(&mut self.sub_level, block_len);
+        params.noise.level.smoothed.next_block(&mut self.noise_level, block_len);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Voice {
     id: VoiceId,
     pub oscillator: Oscillator,
+    velocity: f32,
     velsqrt: f32,
+    /// Random value drawn at note-on, used as the `Random` modulation source.
+    random: f32,
+    /// Polyphonic aftertouch for this voice.
+    pressure: f32,
     params: Arc<VoiceParams>,
     amp: Adsr,
     filter_adsr: Adsr,
     voice_gain: Option<(f32, Smoother<f32>)>,
-    lpf: Ladder,
-    // lpf: LP1,
+    /// Morph between the partial sets built at note-on in the voice slot's [`PartialSets`].
+    morph: Option<Morph>,
+    /// Polyphonic modulation of the morph position, as the normalized offset and a smoother
+    /// replacing the global one.
+    morph_mod: Option<(f32, Smoother<f32>)>,
+    /// Shape and ratio the partials were last synced at, if the waveform supports sync.
+    sync: Option<(SyncShape, f32)>,
+    /// Wavetable position the partials were last set to, if the voice plays the wavetable.
+    wavetable_position: Option<f32>,
+    /// Resonators played instead of the partial bank in [`OscillatorType::Modal`] mode.
+    modal: Option<Modal>,
+    sub: SubOscillator,
+    noise: Noise,
+    /// Filters A and B.
+    filters: [Filter; 2],
+    /// Run the filters at a multiple of the sample rate. The filters keep their state and only
+    /// switch to the new rate when the factor changes. The second oversampler is only used to filter the high partials
+    /// separately in [`FilterRouting::Split`].
+    oversamplers: [Oversampler; 2],
+    lfos: [Lfo; NUM_LFOS],
 }
 
 impl PartialEq for Voice {
@@ -159,18 +375,59 @@ impl PartialEq for Voice {
 impl Eq for Voice {}
 
 impl Voice {
-    pub fn new(osc: Oscillator, id: VoiceId, velocity: f32, params: Arc<VoiceParams>) -> Self {
+    pub fn new(
+        osc: Oscillator,
+        id: VoiceId,
+        velocity: f32,
+        params: Arc<VoiceParams>,
+        prng: &mut Pcg32,
+        clock: u64,
+        morph_sets: &mut PartialSets,
+    ) -> Self {
         let samplerate = osc.samplerate;
+        let lfos = array::from_fn(|i| {
+            let mut lfo = Lfo::new(samplerate, prng.gen());
+            lfo.trigger(&params.lfo[i]);
+            lfo
+        });
         Self {
             id,
             oscillator: osc,
+            velocity,
             velsqrt: velocity.sqrt(),
+            random: prng.gen_range(-1.0..=1.0),
+            pressure: 0.,
             params: params.clone(),
-            amp: Adsr::new(samplerate, params.amp.clone()),
-            filter_adsr: Adsr::new(samplerate, params.filter.clone()),
+            amp: Adsr::new(samplerate, params.amp.clone(), id.note),
+            filter_adsr: Adsr::new(samplerate, params.filter.clone(), id.note),
             voice_gain: None,
-            lpf: Ladder::new(samplerate, params.fhz.value(), params.q.value()),
-            // lpf: LP1::new(samplerate, params.fhz.value()),
+            morph: Morph::new(
+                &params.morph,
+                &osc,
+                util::midi_note_to_freq(id.note),
+                |ty, hz| params.oscillator(ty, samplerate, hz),
+                morph_sets,
+            ),
+            morph_mod: None,
+            sync: SyncShape::from_type(params.osc.value()).map(|shape| (shape, 1.)),
+            wavetable_position: (params.osc.value() == OscillatorType::Wavetable)
+                .then_some(f32::NAN),
+            modal: (params.osc.value() == OscillatorType::Modal).then(|| {
+                let hz = util::midi_note_to_freq(id.note);
+                Modal::new(&params.modal, samplerate, hz, velocity, prng)
+            }),
+            sub: SubOscillator::new(samplerate, util::midi_note_to_freq(id.note)),
+            noise: Noise::new(id.seed(clock)),
+            filters: params.filters().map(|params| {
+                Filter::new(
+                    params.ty.value(),
+                    samplerate,
+                    params.cutoff.value() * key_track(id.note, params.key_tracking.value()),
+                    params.q.value(),
+                )
+            }),
+            oversamplers: [(); 2].map(|_| Oversampler::new(OversamplingFactor::X1)),
+            lfos,
         }
     }
 
@@ -206,21 +463,299 @@ impl Voice {
         smoother
     }
 
-    pub fn next_sample(&mut self) -> f32 {
-        let gain = match self.voice_gain.as_ref() {
-            Some((_, smoother)) => smoother.next(),
-            None => 1.0,
+    /// Polyphonically modulate the morph position by `normalized_offset`. With `reset`, the
+    /// position jumps to the new value instead of gliding to it, which is used for voices started
+    /// in the current block.
+    pub fn set_morph_mod(&mut self, normalized_offset: f32, reset: bool) {
+        let param = &self.params.morph.position;
+        let target = param.preview_modulated(normalized_offset);
+        let (offset, smoother) = self
+            .morph_mod
+            .get_or_insert_with(|| (normalized_offset, param.smoothed.clone()));
+        *offset = normalized_offset;
+        if reset {
+            smoother.reset(target);
+        } else {
+            smoother.set_target(self.oscillator.samplerate, target);
+        }
+    }
+
+    /// Follow automation of the morph position on a voice with polyphonic modulation.
+    pub fn update_morph(&mut self, normalized_value: f32) {
+        if let Some((offset, smoother)) = self.morph_mod.as_mut() {
+            let target = self
+                .params
+                .morph
+                .position
+                .preview_plain(normalized_value + *offset);
+            smoother.set_target(self.oscillator.samplerate, target);
+        }
+    }
+
+    pub fn set_pressure(&mut self, pressure: f32) {
+        self.pressure = pressure;
+    }
+
+    /// Render the voice, adding its output to `left` and `right`.
+    pub fn process_block(
+        &mut self,
+        block: &BlockContext,
+        morph_sets: &PartialSets,
+        left: &mut [f32],
+        right: &mut [f32],
+    ) {
+        let block_len = left.len();
+
+        let mut sources = ModSources::default();
+        for (i, (lfo, params)) in self.lfos.iter_mut().zip(self.params.lfo.iter()).enumerate() {
+            let buffer = &mut sources.get_mut(ModSource::lfo(i))[..block_len];
+            match params.mode.value() {
+                LfoMode::Global => buffer.copy_from_slice(&block.lfos[i][..block_len]),
+                LfoMode::PerVoice => buffer.fill_with(|| lfo.next(params)),
+            }
+        }
+        sources.get_mut(ModSource::AmpEnv)[..block_len].fill_with(|| self.amp.next());
+        sources.get_mut(ModSource::FilterEnv)[..block_len].fill_with(|| self.filter_adsr.next());
+        sources.get_mut(ModSource::Velocity).fill(self.velocity);
+        sources
+            .get_mut(ModSource::Key)
+            .fill((self.id.note as f32 - KEY_TRACK_CENTER as f32) / 64.);
+        sources.get_mut(ModSource::ModWheel)[..block_len]
+            .copy_from_slice(&block.mod_wheel[..block_len]);
+        for (out, pressure) in sources.get_mut(ModSource::Aftertouch)[..block_len]
+            .iter_mut()
+            .zip(block.aftertouch.iter())
+        {
+            *out = pressure.max(self.pressure);
+        }
+        sources.get_mut(ModSource::Random).fill(self.random);
+
+        // The LFOs' own routings behave like extra matrix slots, on top of the macros
+        let mut mods = block.macros.clone();
+        for (i, params) in self.params.lfo.iter().enumerate() {
+            mods.add(
+                params.destination.value(),
+                params.depth.value(),
+                sources.get(ModSource::lfo(i)),
+                block_len,
+            );
+        }
+        mods.evaluate(&self.params.mod_slots, &sources, block_len);
+
+        // Recomputing the partial gains is expensive, and envelope times only apply when a segment
+        // starts, so these destinations are only updated at block rate. The LFO rates are
+        // themselves used to compute the sources, so they lag behind by a block.
+        let spectral = self.spectral_filter(
+            sources.get(ModSource::FilterEnv)[0],
+            mods.get(ModDestination::Cutoff)[0],
+            mods.get(ModDestination::Pitch)[0],
+        );
+        if let Some(morph) = self.morph.as_mut() {
+            let position = match self.morph_mod.as_ref() {
+                Some((_, smoother)) => smoother.next_step(block_len as u32),
+                None => block.morph[0],
+            };
+            morph.apply(
+                morph_sets,
+                &mut self.oscillator,
+                position + mods.get(ModDestination::Morph)[0],
+            );
+        }
+        // The morph takes over the partials when it is on
+        if let (Some((shape, last)), None) = (self.sync.as_mut(), &self.morph) {
+            let ratio = block.sync[0] * mods.get(ModDestination::SyncRatio)[0].exp2();
+            let ratio = ratio.clamp(1., MAX_SYNC_RATIO);
+            if ratio != *last {
+                let hz = util::midi_note_to_freq(self.id.note);
+                self.oscillator.set_sync(*shape, hz, ratio);
+                *last = ratio;
+            }
+        }
+        if let (Some(last), None) = (self.wavetable_position, &self.morph) {
+            let position = block.wavetable_position[0]
+                + mods.get(ModDestination::WavetablePosition)[0];
+            if position != last {
+                if let Ok(wavetable) = self.params.wavetable.table.try_read() {
+                    let hz = util::midi_note_to_freq(self.id.note);
+                    wavetable.apply(&mut self.oscillator, hz, position);
+                    self.wavetable_position = Some(position);
+                }
+            }
+        }
+        // The formants stay put when the pitch is modulated
+        let formant = self.params.formant.filter().map(|formant| FormantFilter {
+            formants: formant
+                .formants
+                .scaled((-mods.get(ModDestination::Pitch)[0] / 12.).exp2()),
+            ..formant
+        });
+        self.oscillator
+            .set_shaping(mods.get(ModDestination::Tilt)[0], spectral, formant);
+        self.oscillator.set_fm(self.params.fm.fm());
+        if let Some(modal) = self.modal.as_mut() {
+            modal.set_decay(self.params.modal.decay.value(), self.params.modal.damping.value());
+        }
+        self.amp.set_time_mod(mods.get(ModDestination::AmpEnvTime)[0]);
+        self.filter_adsr
+            .set_time_mod(mods.get(ModDestination::FilterEnvTime)[0]);
+        for (i, lfo) in self.lfos.iter_mut().enumerate() {
+            lfo.set_rate_mod(mods.get(ModDestination::lfo_rate(i))[0]);
+        }
+        if self.oversamplers[0].factor() != block.oversampling {
+            for oversampler in &mut self.oversamplers {
+                oversampler.set_factor(block.oversampling);
+            }
+            let samplerate = self.filter_samplerate();
+            for (filter, fb) in self.filters.iter_mut().zip(block.filters.iter()) {
+                filter.set_samplerate(samplerate, fb.fhz[0]);
+            }
+        }
+        let samplerate = self.filter_samplerate();
+        for ((filter, params), fb) in self
+            .filters
+            .iter_mut()
+            .zip(self.params.filters())
+            .zip(block.filters.iter())
+        {
+            filter.set_type(params.ty.value(), samplerate, fb.fhz[0], fb.q[0]);
+            filter.set_mode(params.mode.value());
+            filter.set_nonlinearity(params.saturation.value());
+        }
+        let env_modes = self.params.filters().map(|params| params.env_mode.value());
+        let routing = self.params.froute.value();
+        let split = self.params.fsplit.value() as f32;
+        let sub_shape = self.params.sub.shape.value();
+        let sub_octave = self.params.sub.octave.value();
+        let noise_color = self.params.noise.color.value();
+
+        for idx in 0..block_len {
+            let gain = match self.voice_gain.as_ref() {
+                Some((_, smoother)) => smoother.next(),
+                None => 1.0,
+            };
+            let amp = sources.get(ModSource::AmpEnv)[idx]
+                * gain
+                * self.velsqrt
+                * (1. + mods.get(ModDestination::Amplitude)[idx]).max(0.);
+            let fm_index = block.fm_index[idx] + mods.get(ModDestination::FmIndex)[idx];
+            self.oscillator.set_fm_index(fm_index.clamp(0., MAX_FM_INDEX));
+            let env = sources.get(ModSource::FilterEnv)[idx];
+            let da = self.update_filter(0, &block.filters[0], env_modes[0], &mods, env, idx);
+            let db = if routing == FilterRouting::Single {
+                1.
+            } else {
+                self.update_filter(1, &block.filters[1], env_modes[1], &mods, env, idx)
+            };
+
+            let pitch = (mods.get(ModDestination::Pitch)[idx] / 12.).exp2();
+            // Only the split routing uses the high partials, the others get everything in `osc`
+            let [osc, high] = match (self.modal.as_mut(), routing) {
+                (Some(modal), FilterRouting::Split) => modal.sample_split(pitch, split),
+                (Some(modal), _) => [modal.sample(pitch), 0.],
+                (None, FilterRouting::Split) => self.oscillator.sample_split(pitch, split),
+                (None, _) => [self.oscillator.sample(pitch), 0.],
+            };
+            // The sub and noise layers go with the low partials in the split routing. They are
+            // skipped while they are silent, the sub only keeping its phase running.
+            let mut osc = osc;
+            let (sub_level, noise_level) = (block.sub_level[idx], block.noise_level[idx]);
+            if sub_level != 0. {
+                osc += sub_level * self.sub.next(sub_shape, sub_octave, pitch);
+            } else {
+                self.sub.skip(sub_octave, pitch);
+            }
+            if noise_level != 0. {
+                osc += noise_level * self.noise.next(noise_color);
+            }
+            let [a, b] = &mut self.filters;
+            let [os_a, os_b] = &mut self.oversamplers;
+            // The drive is undone after each filter so that it only changes the saturation
+            let y = amp
+                * match routing {
+                    FilterRouting::Single => os_a.process(osc, |x| a.process_sample(x * da) / da),
+                    FilterRouting::Serial => os_a.process(osc, |x| {
+                        b.process_sample(a.process_sample(x * da) / da * db) / db
+                    }),
+                    FilterRouting::Parallel => os_a.process(osc, |x| {
+                        a.process_sample(x * da) / da + b.process_sample(x * db) / db
+                    }),
+                    FilterRouting::Split => {
+                        os_a.process(osc, |x| a.process_sample(x * da) / da)
+                            + os_b.process(high, |x| b.process_sample(x * db) / db)
+                    }
+                };
+
+            // Constant power panning, scaled so that a centered voice keeps unity gain
+            let angle = (mods.get(ModDestination::Pan)[idx].clamp(-1., 1.) + 1.) * FRAC_PI_4;
+            left[idx] += y * angle.cos() * SQRT_2;
+            right[idx] += y * angle.sin() * SQRT_2;
+        }
+    }
+
+    /// Spectral filter for the current block, given the filter envelope, the cutoff modulation in
+    /// octaves and the pitch modulation in semitones. The response is evaluated at the unmodulated
+    /// partial frequencies, so the pitch modulation moves the cutoff in the other direction.
+    fn spectral_filter(&self, env: f32, cutoff_mod: f32, pitch: f32) -> Option<SpectralFilter> {
+        let params = &self.params.spectral;
+        let shape = params.shape.value();
+        if shape == SpectralShape::Off {
+            return None;
+        }
+        let key_track = key_track(self.id.note, params.key_tracking.value());
+        let fc = FilterEnvMode::Exponential.cutoff(
+            params.cutoff.value() * key_track,
+            env,
+            params.env_octaves.value(),
+            cutoff_mod,
+            self.oscillator.samplerate,
+        );
+        Some(SpectralFilter {
+            shape,
+            fc: fc * (-pitch / 12.).exp2(),
+            q: params.q.value(),
+        })
+    }
+
+    /// Update the cutoff, resonance and compensation of filter `i` for sample `idx` of the block,
+    /// returning its drive as a linear gain.
+    #[inline(always)]
+    fn update_filter(
+        &mut self,
+        i: usize,
+        block: &FilterBlock,
+        env_mode: FilterEnvMode,
+        mods: &ModBuffers,
+        env: f32,
+        idx: usize,
+    ) -> f32 {
+        let key_track = key_track(self.id.note, block.fkt[idx]);
+        // The modulation depth is relative to the range of the amount in either mode
+        let fmod_mod = mods.get(ModDestination::FilterEnvAmount)[idx];
+        let fmod = match env_mode {
+            FilterEnvMode::Linear => block.fmod[idx] + fmod_mod,
+            FilterEnvMode::Exponential => {
+                block.fmodoct[idx]
+                    + fmod_mod / ModDestination::FilterEnvAmount.range() * MAX_ENV_OCTAVES
+            }
         };
-        let amp = self.amp.next() * gain * self.velsqrt;
-        let drive = util::db_to_gain(self.params.drive.smoothed.next());
-        self.lpf.set_fc(
-            self.params.fhz.smoothed.next()
-                + self.filter_adsr.next() * self.params.fmod.smoothed.next(),
+        let filter = &mut self.filters[i];
+        filter.set_fc(env_mode.cutoff(
+            block.fhz[idx] * key_track,
+            env,
+            fmod,
+            mods.get(ModDestination::Cutoff)[idx],
+            self.oscillator.samplerate,
+        ));
+        filter.set_resonance(
+            (block.q[idx] + mods.get(ModDestination::Resonance)[idx]).clamp(0., 16.),
         );
-        self.lpf.set_resonance(self.params.q.smoothed.next());
+        filter.set_compensation(block.fcomp[idx]);
+        db_to_gain(block.drive[idx] + mods.get(ModDestination::Drive)[idx])
+    }
 
-        let osc = self.oscillator.sample();
-        amp * self.lpf.process_sample(osc * drive) / drive
+    /// Sample rate the filters run at, after oversampling.
+    fn filter_samplerate(&self) -> f32 {
+        self.oscillator.samplerate * self.oversamplers[0].factor().ratio() as f32
     }
 
     pub fn channel(&self) -> u8 {
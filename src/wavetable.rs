@@ -0,0 +1,357 @@
+use std::array;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use std::io::Read;
+use std::path::Path;
+use std::simd::f32x8;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use nih_plug::prelude::*;
+use num_complex::Complex;
+use rustfft::FftPlanner;
+use serde::{Deserialize, Serialize};
+
+use crate::harmonics::{Harmonic, HarmonicTable};
+use crate::oscillator::Oscillator;
+
+/// Number of samples in a frame of a Serum or WaveEdit wavetable.
+pub const FRAME_SIZE: usize = 2048;
+/// Maximum number of frames loaded from a file, the rest is ignored.
+pub const MAX_FRAMES: usize = 256;
+
+#[derive(Debug)]
+pub enum WavetableError {
+    Wav(hound::Error),
+    /// The file is shorter than a single frame.
+    TooShort(usize),
+    /// The samples stored with the plugin state aren't valid base64.
+    Base64(base64::DecodeError),
+}
+
+impl fmt::Display for WavetableError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Wav(err) => write!(f, "Cannot read wavetable: {err}"),
+            Self::TooShort(len) => write!(
+                f,
+                "Wavetable has {len} samples, at least one frame of {FRAME_SIZE} is needed"
+            ),
+            Self::Base64(err) => write!(f, "Cannot decode stored wavetable: {err}"),
+        }
+    }
+}
+
+impl Error for WavetableError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Wav(err) => Some(err),
+            Self::TooShort(_) => None,
+            Self::Base64(err) => Some(err),
+        }
+    }
+}
+
+impl From<hound::Error> for WavetableError {
+    fn from(err: hound::Error) -> Self {
+        Self::Wav(err)
+    }
+}
+
+impl From<base64::DecodeError> for WavetableError {
+    fn from(err: base64::DecodeError) -> Self {
+        Self::Base64(err)
+    }
+}
+
+/// Single-cycle frames converted to harmonic tables, so that they can be played by the partial
+/// bank without aliasing at any pitch.
+///
+/// Only the frames' samples are stored with the plugin state, and the harmonic tables are analyzed
+/// again when it is loaded. Those are much larger as text, especially for frames that aren't
+/// band-limited.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StoredWavetable", into = "StoredWavetable")]
+pub struct Wavetable {
+    pub frames: Vec<HarmonicTable>,
+    /// Samples of the frames, back to back.
+    samples: Vec<f32>,
+}
+
+/// Serialized form of a [`Wavetable`]: its samples as little-endian `f32`s, encoded in base64.
+#[derive(Serialize, Deserialize)]
+struct StoredWavetable {
+    samples: String,
+}
+
+impl From<Wavetable> for StoredWavetable {
+    fn from(wavetable: Wavetable) -> Self {
+        let bytes: Vec<u8> = wavetable.samples.iter().flat_map(|x| x.to_le_bytes()).collect();
+        Self {
+            samples: base64::encode(bytes),
+        }
+    }
+}
+
+impl TryFrom<StoredWavetable> for Wavetable {
+    type Error = WavetableError;
+
+    fn try_from(stored: StoredWavetable) -> Result<Self, Self::Error> {
+        let bytes = base64::decode(stored.samples)?;
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        let samples: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+            .collect();
+        Self::from_samples(&samples)
+    }
+}
+
+impl Wavetable {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, WavetableError> {
+        Self::from_wav(std::fs::File::open(path).map_err(hound::Error::from)?)
+    }
+
+    /// Read a wavetable from a WAV file of consecutive frames of [`FRAME_SIZE`] samples.
+    /// Multichannel files are mixed down to mono, and an incomplete last frame is dropped.
+    pub fn from_wav(reader: impl Read) -> Result<Self, WavetableError> {
+        let reader = hound::WavReader::new(reader)?;
+        let spec = reader.spec();
+        let samples: Vec<f32> = match spec.sample_format {
+            hound::SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<_, _>>()?,
+            hound::SampleFormat::Int => {
+                let scale = 1. / (1u32 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .into_samples::<i32>()
+                    .map(|s| s.map(|s| s as f32 * scale))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        let channels = spec.channels as usize;
+        let mono: Vec<f32> = samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+            .collect();
+        Self::from_samples(&mono)
+    }
+
+    pub fn from_samples(samples: &[f32]) -> Result<Self, WavetableError> {
+        if samples.len() < FRAME_SIZE {
+            return Err(WavetableError::TooShort(samples.len()));
+        }
+        let samples = &samples[..(samples.len() / FRAME_SIZE).min(MAX_FRAMES) * FRAME_SIZE];
+        let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+        let mut buffer = vec![Complex::default(); FRAME_SIZE];
+        let frames = samples
+            .chunks_exact(FRAME_SIZE)
+            .map(|frame| {
+                for (x, &sample) in buffer.iter_mut().zip(frame) {
+                    *x = Complex::new(sample, 0.);
+                }
+                fft.process(&mut buffer);
+                // Bin k is the k-th harmonic of the cycle. DC is dropped, and so is the Nyquist
+                // bin, which has no well-defined phase. The partials are sines, hence the
+                // quarter-turn from the cosines of the FFT.
+                let harmonics = buffer[1..FRAME_SIZE / 2]
+                    .iter()
+                    .map(|x| Harmonic {
+                        amplitude: 2. * x.norm() / FRAME_SIZE as f32,
+                        phase: x.arg() + std::f32::consts::FRAC_PI_2,
+                    })
+                    .collect();
+                HarmonicTable(harmonics)
+            })
+            .collect();
+        Ok(Self {
+            frames,
+            samples: samples.to_vec(),
+        })
+    }
+
+    /// Frame the phases of the partials are taken from when a note starts at `position`.
+    pub fn frame(&self, position: f32) -> Option<&HarmonicTable> {
+        self.frames_at(position).map(|(a, _, _)| a)
+    }
+
+    /// The two frames around `position`, between 0 and 1, and the interpolation factor between
+    /// them.
+    fn frames_at(&self, position: f32) -> Option<(&HarmonicTable, &HarmonicTable, f32)> {
+        let last = self.frames.len().checked_sub(1)?;
+        let x = position.clamp(0., 1.) * last as f32;
+        let i = x as usize;
+        let next = &self.frames[(i + 1).min(last)];
+        Some((&self.frames[i], next, x - i as f32))
+    }
+
+    /// Set the partials of `osc`, playing a note at `hz`, to the frame at `position`. Amplitudes
+    /// are interpolated between frames, while the phases stay those of the frame the oscillator was
+    /// built from, so that the partials keep running smoothly.
+    pub fn apply(&self, osc: &mut Oscillator, hz: f32, position: f32) {
+        let Some((a, b, t)) = self.frames_at(position) else {
+            return;
+        };
+        osc.set_partials(|k| {
+            let n = |j: usize| 8 * k + j + 1;
+            let gains = array::from_fn(|j| {
+                let (a, b) = (a.get(n(j)).amplitude, b.get(n(j)).amplitude);
+                a + t * (b - a)
+            });
+            let hz = array::from_fn(|j| n(j) as f32 * hz);
+            (f32x8::from_array(gains), f32x8::from_array(hz))
+        });
+    }
+}
+
+#[derive(Params)]
+pub struct WavetableParams {
+    #[id = "wtpos"]
+    pub position: FloatParam,
+    /// The loaded wavetable, set through [`WavetableParams::load`] or [`WavetableParams::set`].
+    #[persist = "wavetable"]
+    pub table: Arc<RwLock<Wavetable>>,
+}
+
+impl fmt::Debug for WavetableParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WavetableParams").finish_non_exhaustive()
+    }
+}
+
+impl Default for WavetableParams {
+    fn default() -> Self {
+        Self {
+            position: FloatParam::new(
+                "Wavetable Position",
+                0.,
+                FloatRange::Linear { min: 0., max: 1. },
+            )
+            .with_string_to_value(formatters::s2v_f32_percentage())
+            .with_value_to_string(formatters::v2s_f32_percentage(2))
+            .with_smoother(SmoothingStyle::Linear(20.)),
+            table: Arc::new(RwLock::new(Wavetable::default())),
+        }
+    }
+}
+
+impl WavetableParams {
+    /// Load a wavetable from a WAV file, replacing the current one. This decodes and analyzes the
+    /// whole file, so it must be called from the GUI or another background thread. Voices keep
+    /// playing the previous table until the new one is swapped in.
+    pub fn load(&self, path: impl AsRef<Path>) -> Result<(), WavetableError> {
+        self.set(Wavetable::open(path)?);
+        Ok(())
+    }
+
+    pub fn set(&self, wavetable: Wavetable) {
+        *self.table.write().unwrap_or_else(PoisonError::into_inner) = wavetable;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+    use std::io::Cursor;
+
+    use super::{Wavetable, WavetableError, WavetableParams, FRAME_SIZE};
+    use crate::oscillator::Oscillator;
+
+    fn sine(harmonic: usize) -> impl Iterator<Item = f32> {
+        (0..FRAME_SIZE).map(move |i| (TAU * (harmonic * i) as f32 / FRAME_SIZE as f32).sin())
+    }
+
+    #[test]
+    fn frames_become_harmonics() {
+        let samples: Vec<f32> = sine(1).chain(sine(3).map(|x| -0.5 * x)).collect();
+        let wavetable = Wavetable::from_samples(&samples).unwrap();
+        assert_eq!(2, wavetable.frames.len());
+
+        let [a, b] = [&wavetable.frames[0], &wavetable.frames[1]];
+        assert_eq!(FRAME_SIZE / 2 - 1, a.0.len());
+        approx::assert_abs_diff_eq!(1., a.get(1).amplitude, epsilon = 1e-4);
+        approx::assert_abs_diff_eq!(0., a.get(1).phase, epsilon = 1e-4);
+        approx::assert_abs_diff_eq!(0., a.get(2).amplitude, epsilon = 1e-4);
+        approx::assert_abs_diff_eq!(0.5, b.get(3).amplitude, epsilon = 1e-4);
+        approx::assert_abs_diff_eq!(
+            std::f32::consts::PI,
+            b.get(3).phase.rem_euclid(TAU),
+            epsilon = 1e-3
+        );
+    }
+
+    #[test]
+    fn position_interpolates_amplitudes() {
+        let samples: Vec<f32> = sine(1).chain(sine(2)).collect();
+        let wavetable = Wavetable::from_samples(&samples).unwrap();
+        let mut osc = Oscillator::from_harmonics(48e3, 100., wavetable.frame(0.).unwrap());
+        wavetable.apply(&mut osc, 100., 0.25);
+        approx::assert_abs_diff_eq!(0.75, osc.gains[0][0], epsilon = 1e-4);
+        approx::assert_abs_diff_eq!(0.25, osc.gains[0][1], epsilon = 1e-4);
+        assert_eq!(200., osc.phasors[0].hz[1]);
+    }
+
+    /// A stereo 16-bit WAV file of two sine frames and part of a third.
+    fn wav() -> Vec<u8> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut data = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut data, spec).unwrap();
+        for x in sine(1).chain(sine(1)).chain(sine(1).take(100)) {
+            let x = (x * i16::MAX as f32) as i16;
+            writer.write_sample(x).unwrap();
+            writer.write_sample(x).unwrap();
+        }
+        writer.finalize().unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn reads_wav() {
+        let wavetable = Wavetable::from_wav(Cursor::new(wav())).unwrap();
+        assert_eq!(2, wavetable.frames.len());
+        approx::assert_abs_diff_eq!(1., wavetable.frames[1].get(1).amplitude, epsilon = 1e-3);
+
+        assert!(matches!(
+            Wavetable::from_samples(&[0.; 100]),
+            Err(WavetableError::TooShort(100))
+        ));
+        assert!(Wavetable::default().frame(0.5).is_none());
+    }
+
+    #[test]
+    fn state_stores_samples() {
+        let samples: Vec<f32> = sine(1).chain(sine(5).map(|x| x.signum())).collect();
+        let wavetable = Wavetable::from_samples(&samples).unwrap();
+        let json = serde_json::to_string(&wavetable).unwrap();
+        // Four bytes per sample, inflated by a third by base64
+        assert!(json.len() < samples.len() * 4 * 4 / 3 + 100);
+        assert_eq!(wavetable, serde_json::from_str(&json).unwrap());
+
+        let empty = serde_json::to_string(&Wavetable::default()).unwrap();
+        assert_eq!(Wavetable::default(), serde_json::from_str(&empty).unwrap());
+        assert!(serde_json::from_str::<Wavetable>(r#"{"samples": "not base64!"}"#).is_err());
+    }
+
+    #[test]
+    fn params_load_files() {
+        let path = std::env::temp_dir().join(format!("wavetable-{}.wav", std::process::id()));
+        std::fs::write(&path, wav()).unwrap();
+        let params = WavetableParams::default();
+        let loaded = params.load(&path);
+        std::fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(2, params.table.read().unwrap().frames.len());
+
+        // A failed load keeps the previous table
+        assert!(matches!(
+            params.load(path.with_extension("missing")),
+            Err(WavetableError::Wav(_))
+        ));
+        assert_eq!(2, params.table.read().unwrap().frames.len());
+    }
+}